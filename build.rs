@@ -29,6 +29,17 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // The executor task arena size, for the telemetry, from the `task-arena-size-*`
+    // feature enabled on embassy-executor.
+    let manifest = std::fs::read_to_string("Cargo.toml").unwrap();
+    let arena_size = manifest
+        .split("task-arena-size-")
+        .nth(1)
+        .map(|rest| rest.split(|c: char| !c.is_ascii_digit()).next().unwrap())
+        .expect("no task-arena-size feature on embassy-executor");
+    println!("cargo:rustc-env=TASK_ARENA_SIZE={}", arena_size);
+    println!("cargo:rerun-if-changed=Cargo.toml");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
@@ -8,48 +8,29 @@
 use core::str::FromStr;
 use core::{ops, u16};
 
-use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_rp as rp;
-use embassy_rp::bind_interrupts;
-use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Level, Output};
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
-use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use embassy_sync::channel::Channel;
 use embassy_sync::signal::Signal;
 use embassy_time::{Duration, Timer};
 use heapless::String;
-use itertools::Itertools;
-use rand::RngCore;
-use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+use mqtt_pico::board::{self, RadioPeripherals};
+use mqtt_pico::input::messages::*;
 use mqtt_pico::output::leds::*;
 
-bind_interrupts!(struct Irqs {
-    PIO0_IRQ_0 => InterruptHandler<PIO0>;
-});
-
-#[embassy_executor::task]
-async fn cyw43_task(
-    runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
-) -> ! {
-    runner.run().await
-}
-
-#[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
-    runner.run().await
-}
-
 static LED_COUNT: usize = 2;
 
 static LED_SIGNALS: [Signal<ThreadModeRawMutex, LedStatus>; LED_COUNT] =
     [const { Signal::new() }; LED_COUNT];
 
+static LED_STATES: [Signal<ThreadModeRawMutex, LedStatus>; LED_COUNT] =
+    [const { Signal::new() }; LED_COUNT];
+
 #[embassy_executor::task(pool_size = 2)]
 async fn led_task(
     mut led: RgbLed<'static>,
@@ -66,104 +47,16 @@ async fn pwm_led_task(
     led.task(signal).await
 }
 
-struct ChannelMessage {
-    topic: String<12>,
-    id: String<8>,
-    data: String<8>,
-    payload: String<32>,
-}
-static INPUT_CHANNEL: Channel<ThreadModeRawMutex, ChannelMessage, 6> = Channel::new();
+static INPUT_CHANNEL: InputChannel = Channel::new();
 
 #[embassy_executor::task]
-async fn message_parser() -> ! {
-    let mut leds = [LedStatus {
-        anim: Anim::None,
-        color: None,
-        power: None,
-    }; LED_COUNT];
-    loop {
-        let ChannelMessage {
-            topic,
-            id,
-            data,
-            payload,
-        } = INPUT_CHANNEL.receive().await;
-        let id: usize = if let Ok(v) = id.parse() {
-            v
-        } else {
-            warn!("invalid id : {}", id);
-            continue;
-        };
-        match topic.as_str() {
-            "led" => {
-                let led = if id == 0 || id > LED_SIGNALS.len() {
-                    continue;
-                } else {
-                    &mut leds[id - 1]
-                };
-                match data.as_str() {
-                    "color" => {
-                        let len = payload.chars().count();
-                        if len == 0 {
-                            led.color = None;
-                        } else if len == 1 {
-                            match payload.chars().nth(0) {
-                                None => core::unreachable!("length has been checked to be 1"),
-                                Some('R') | Some('r') => led.color = Some(Color::red()),
-                                Some('G') | Some('g') => led.color = Some(Color::green()),
-                                Some('B') | Some('b') => led.color = Some(Color::blue()),
-                                Some('0') | Some('O') | Some('o') => led.color = Some(Color::off()),
-                                Some(x) => warn!("unknown color {}", x),
-                            }
-                        } else if len == 4 && payload.starts_with("#") {
-                            let (r, g, b) = payload
-                                .chars()
-                                .skip(1)
-                                .map(|s| s.to_digit(16).unwrap_or_default() << 4)
-                                .collect_tuple()
-                                .unwrap();
-                            led.color = Some(Color::new(r as u8, g as u8, b as u8));
-                        } else {
-                            warn!("can not understand message : {}", payload);
-                            continue;
-                        }
-                    }
-                    "power" => {
-                        let len = payload.chars().count();
-                        if len == 0 {
-                            led.power = None;
-                        } else {
-                            led.power = payload.parse().ok();
-                        }
-                    }
-                    "anim" => {}
-                    _ => {
-                        warn!("invalid data source : {}", data);
-                        continue;
-                    }
-                }
-                LED_SIGNALS[id - 1].signal(led.clone());
-            }
-            _ => warn!("topic unknown : {}", topic),
-        }
-    }
+async fn message_parser_task() -> ! {
+    message_parser(&INPUT_CHANNEL, &LED_SIGNALS, &LED_STATES, None).await
 }
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
-    //let fw = include_bytes!("../../../embassy/cyw43-firmware/43439A0.bin");
-    //let clm = include_bytes!("../../../embassy/cyw43-firmware/43439A0_clm.bin");
-    //let wifi_password = core::env!("WIFI_PASSWORD", "No wifi password set");
-    //let wifi_ssid = core::env!("WIFI_SSID", "No wifi SSID set");
-
-    // To make flashing faster for development, you may want to flash the firmwares independently
-    // at hardcoded addresses, instead of baking them into the program with `include_bytes!`:
-    //     probe-rs download ../../cyw43-firmware/43439A0.bin --binary-format bin --chip RP2040 --base-address 0x10100000
-    //     probe-rs download ../../cyw43-firmware/43439A0_clm.bin --binary-format bin --chip RP2040 --base-address 0x10140000
-    let fw = unsafe { core::slice::from_raw_parts(0x10100000 as *const u8, 230321) };
-    let clm = unsafe { core::slice::from_raw_parts(0x10140000 as *const u8, 4752) };
-
     let chip_id_num = embassy_rp::pac::SYSINFO.chip_id().read();
 
     info!("CHIP ID IS : {:x}", chip_id_num.0);
@@ -176,6 +69,8 @@ async fn main(spawner: Spawner) {
         green: Output::new(p.PIN_12, Level::High),
         blue: Output::new(p.PIN_11, Level::High),
     };
+    // a missing radio firmware blinks the red of the first led, which is active low
+    board::check_firmware(&mut led1.red, Level::Low, board::WIFI_FIRMWARE).await;
 
     /*let mut led2 = RgbLed{
         red: Output::new(p.PIN_10, Level::High),
@@ -205,33 +100,18 @@ async fn main(spawner: Spawner) {
     unwrap!(spawner.spawn(led_task(led1, &LED_SIGNALS[0])));
     unwrap!(spawner.spawn(pwm_led_task(led2, &LED_SIGNALS[1])));
 
-    unwrap!(spawner.spawn(message_parser()));
+    unwrap!(spawner.spawn(message_parser_task()));
 
-    let pwr = Output::new(p.PIN_23, Level::Low);
-    let cs = Output::new(p.PIN_25, Level::High);
-    let mut pio = Pio::new(p.PIO0, Irqs);
-    let spi = PioSpi::new(
-        &mut pio.common,
-        pio.sm0,
-        pio.irq0,
-        cs,
-        p.PIN_24,
-        p.PIN_29,
-        p.DMA_CH0,
-    );
-
-    static STATE: StaticCell<cyw43::State> = StaticCell::new();
-    let state = STATE.init(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
-    unwrap!(spawner.spawn(cyw43_task(runner)));
-
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
-
-    let mut rng = RoscRng;
-    let seed = rng.next_u64();
+    let radio = RadioPeripherals {
+        pwr: p.PIN_23,
+        cs: p.PIN_25,
+        dio: p.PIN_24,
+        clk: p.PIN_29,
+        pio: p.PIO0,
+        dma: p.DMA_CH0,
+    };
+    // only the onboard led is used
+    let (_net_device, mut control) = board::init_radio(spawner, radio).await;
 
     INPUT_CHANNEL
         .send(ChannelMessage {
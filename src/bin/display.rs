@@ -6,38 +6,18 @@
 #![no_main]
 
 use cyw43::{Control, JoinOptions};
-use cyw43_pio::PioSpi;
 use defmt::*;
 use embassy_executor::Spawner;
 use embassy_futures::select::select4;
-use embassy_net::StackResources;
-use embassy_rp::{bind_interrupts, Peripheral};
-use embassy_rp::clocks::RoscRng;
+use embassy_rp::Peripheral;
 use embassy_rp::gpio::{Input, Level, Output, Pull};
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
-use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_time::{Duration, Timer};
 use static_cell::StaticCell;
-use rand::RngCore;
 use {defmt_rtt as _, panic_probe as _};
-use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
-
-
 
-
-bind_interrupts!(struct Irqs {
-    PIO0_IRQ_0 => InterruptHandler<PIO0>;
-});
-
-#[embassy_executor::task]
-async fn cyw43_task(runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>) -> ! {
-    runner.run().await
-}
-
-#[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
-    runner.run().await
-}
+use mqtt_pico::board::{self, RadioPeripherals};
+use mqtt_pico::config::IpSettings;
+use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
 
 use embassy_sync::channel::Channel;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
@@ -49,39 +29,18 @@ static SPI_BUS: StaticCell<NoopMutex<RefCell<Spim<SPI3>>>> = StaticCell::new();
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
-    //let fw = include_bytes!("../../../embassy/cyw43-firmware/43439A0.bin");
-    //let clm = include_bytes!("../../../embassy/cyw43-firmware/43439A0_clm.bin");
-
-    // To make flashing faster for development, you may want to flash the firmwares independently
-    // at hardcoded addresses, instead of baking them into the program with `include_bytes!`:
-    //     probe-rs download ../../cyw43-firmware/43439A0.bin --binary-format bin --chip RP2040 --base-address 0x10100000
-    //     probe-rs download ../../cyw43-firmware/43439A0_clm.bin --binary-format bin --chip RP2040 --base-address 0x10140000
-    let fw = unsafe { core::slice::from_raw_parts(0x10100000 as *const u8, 230321) };
-    let clm = unsafe { core::slice::from_raw_parts(0x10140000 as *const u8, 4752) };
-
-    let pwr = Output::new(p.PIN_23, Level::Low);
-    let cs = Output::new(p.PIN_25, Level::High);
-    let mut pio = Pio::new(p.PIO0, Irqs);
-    let spi = PioSpi::new(&mut pio.common, pio.sm0, pio.irq0, cs, p.PIN_24, p.PIN_29, p.DMA_CH0);
-
-    static STATE: StaticCell<cyw43::State> = StaticCell::new();
-    let state = STATE.init(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
-    unwrap!(spawner.spawn(cyw43_task(runner)));
-
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
-
-
-    let config = embassy_net::Config::dhcpv4(Default::default());
-
-    let mut rng = RoscRng;
-    let seed = rng.next_u64();    
-    
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
-    let (stack, runner) = embassy_net::new(net_device, config, RESOURCES.init(StackResources::new()), seed);
+    // a missing radio firmware blinks the red of the first led, which is active low
+    let mut status_led = Output::new(p.PIN_13, Level::High);
+    board::check_firmware(&mut status_led, Level::Low, board::WIFI_FIRMWARE).await;
+    let radio = RadioPeripherals {
+        pwr: p.PIN_23,
+        cs: p.PIN_25,
+        dio: p.PIN_24,
+        clk: p.PIN_29,
+        pio: p.PIO0,
+        dma: p.DMA_CH0,
+    };
+    let (_stack, control) = board::init_network(spawner, radio, IpSettings::from_env().stack_config()).await;
 
     let spi_dev = SpiDevice::new(SPI_BUS.init(NoopMutex::new(RefCell::new(spi))));
 
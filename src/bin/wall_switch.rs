@@ -1,188 +1,838 @@
 #![no_std]
 #![no_main]
 
-use cyw43::{JoinOptions, ScanOptions};
-use cyw43_pio::PioSpi;
+use core::cell::Cell;
+use core::pin::pin;
+
+#[cfg(not(feature = "ethernet-w5500"))]
+use bt_hci::controller::ExternalController;
+#[cfg(not(feature = "ethernet-w5500"))]
+use cyw43::bluetooth::BtDriver;
 use defmt::*;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select3, select_array, Either, Either3};
 use embassy_net::tcp::{TcpSocket};
-use embassy_net::{IpAddress, IpEndpoint, StackResources};
-use embassy_rp::bind_interrupts;
+use embassy_net::Stack;
+use embassy_rp as rp;
+#[cfg(feature = "tls")]
 use embassy_rp::clocks::RoscRng;
-use embassy_rp::gpio::{Level, Output};
-use embassy_rp::peripherals::{DMA_CH0, PIO0};
-use embassy_rp::pio::{InterruptHandler, Pio};
-use embassy_time::{Duration, Timer};
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embedded_io_async::{ErrorKind, Read, Write};
+#[cfg(feature = "tls")]
+use embedded_tls::{TlsConfig, TlsConnection, TlsContext};
+#[cfg(feature = "tls-client-cert")]
+use embedded_tls::Certificate;
 use heapless::{String, Vec};
-use rust_mqtt::client::client::MqttClient;
-use rust_mqtt::client::client_config::ClientConfig;
-use rust_mqtt::utils::rng_generator::CountingRng;
 use static_cell::StaticCell;
-use rand::RngCore;
+#[cfg(not(feature = "ethernet-w5500"))]
+use trouble_host::Address;
 use {defmt_rtt as _, panic_probe as _};
 
-
-bind_interrupts!(struct Irqs {
-    PIO0_IRQ_0 => InterruptHandler<PIO0>;
-});
+#[cfg(not(feature = "ethernet-w5500"))]
+use mqtt_pico::ble::{self, leds::Channels};
+#[cfg(not(feature = "ethernet-w5500"))]
+use mqtt_pico::board::RadioPeripherals;
+use mqtt_pico::{board, clock};
+use mqtt_pico::config::{BrokerConfig, Groups, IpSettings, KnownNetworks, Location, MqttCredentials, MqttSettings};
+use mqtt_pico::input::buttons::*;
+use mqtt_pico::input::messages::*;
+use mqtt_pico::logging::{self, LOG_CHANNEL};
+use mqtt_pico::{log_error, log_warn};
+use mqtt_pico::mqtt::buffers::*;
+use mqtt_pico::mqtt::client::{ConnectOptions, Error as MqttError, Event, MqttClient, SessionState};
+use mqtt_pico::mqtt::delivery::{Outbox, Qos};
+use mqtt_pico::mqtt::{discovery, flush_outbox, publish, resume_outbox, PublishError};
+use mqtt_pico::net::wifi;
+use mqtt_pico::net::{broker, ip, power, provisioning, sntp};
+#[cfg(feature = "ethernet-w5500")]
+use mqtt_pico::net::ethernet::{self, EthernetPins};
+use mqtt_pico::mqtt::json::{LedState, MAX_JSON_PAYLOAD};
+use mqtt_pico::mqtt::rpc::{self, Call, Command, Status};
+use mqtt_pico::mqtt::transport::TrackedConnection;
+#[cfg(feature = "tls")]
+use mqtt_pico::mqtt::tls::*;
+use mqtt_pico::output::leds::*;
+use mqtt_pico::schedule::Scheduler;
+use mqtt_pico::storage::{SharedStorage, Storage};
+use mqtt_pico::telemetry::{self, Telemetry, TELEMETRY_INTERVAL};
+
+#[cfg(feature = "tls")]
+const BROKER_PORT: u16 = 8883;
+#[cfg(not(feature = "tls"))]
+const BROKER_PORT: u16 = 1883;
 
 #[embassy_executor::task]
-async fn cyw43_task(runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>) -> ! {
-    runner.run().await
+async fn sntp_task(stack: Stack<'static>) -> ! {
+    sntp::run(stack, option_env!("SNTP_SERVER").unwrap_or(sntp::DEFAULT_SERVER)).await
 }
 
+#[cfg(not(feature = "ethernet-w5500"))]
 #[embassy_executor::task]
-async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
-    runner.run().await
+async fn wifi_task(control: &'static wifi::SharedControl, stack: Stack<'static>, storage: &'static SharedStorage) -> ! {
+    wifi::run(control, stack, storage).await
 }
 
-#[embassy_executor::main]
-async fn main(spawner: Spawner) {
-    let p = embassy_rp::init(Default::default());
+#[cfg(not(feature = "ethernet-w5500"))]
+#[embassy_executor::task]
+async fn status_led_task(control: &'static wifi::SharedControl) -> ! {
+    wifi::status_led(control).await
+}
 
-    // Wifi chip firmware
+#[cfg(not(feature = "ethernet-w5500"))]
+#[embassy_executor::task]
+async fn ble_task(
+    controller: ExternalController<BtDriver<'static>, 10>,
+    address: Address,
+    chip_id: String<12>,
+    storage: &'static SharedStorage,
+    provisioning: bool,
+) -> ! {
+    let channels = Channels {
+        input: &INPUT_CHANNEL,
+        states: &BLE_LED_STATES,
+        buttons: &BLE_BUTTONS,
+    };
+    ble::run(controller, address, &chip_id, storage, channels, provisioning).await
+}
 
-    //let fw = include_bytes!("../../../embassy/cyw43-firmware/43439A0.bin");
-    //let clm = include_bytes!("../../../embassy/cyw43-firmware/43439A0_clm.bin");
+/// Copies of the led changes and button events, notified over bluetooth.
+static BLE_LED_STATES: LedStateChannel = Channel::new();
+static BLE_BUTTONS: ButtonChannel = Channel::new();
 
-    // To make flashing faster for development, you may want to flash the firmwares independently
-    // at hardcoded addresses, instead of baking them into the program with `include_bytes!`:
-    //     probe-rs download ../../cyw43-firmware/43439A0.bin --binary-format bin --chip RP2040 --base-address 0x10100000
-    //     probe-rs download ../../cyw43-firmware/43439A0_clm.bin --binary-format bin --chip RP2040 --base-address 0x10140000
-    let fw = unsafe { core::slice::from_raw_parts(0x10100000 as *const u8, 230321) };
-    let clm = unsafe { core::slice::from_raw_parts(0x10140000 as *const u8, 4752) };
-    
-    // Secrets
-    let wifi_password = core::env!("WIFI_PASSWORD", "No wifi password set");
-    let wifi_ssid = core::env!("WIFI_SSID", "No wifi SSID set");
+#[embassy_executor::task]
+async fn ip_task(stack: Stack<'static>, settings: IpSettings) -> ! {
+    ip::run(stack, &settings).await
+}
 
-    // Constants
-    let chip_id:heapless::String<12> = {
-        let chip_id_num = embassy_rp::pac::SYSINFO.chip_id().read();
-        info!("CHIP ID IS : {:x}", chip_id_num.0);
-        let mut chip_id: heapless::String<12> = heapless::String::new();
-        core::fmt::write(&mut chip_id, format_args!("ksl-{:X}", chip_id_num.0));
-        chip_id
-    };
-    let prefix = "embedded";
+static LED_COUNT: usize = 2;
 
+static LED_SIGNALS: [Signal<ThreadModeRawMutex, LedStatus>; LED_COUNT] =
+    [const { Signal::new() }; LED_COUNT];
 
-    let pwr = Output::new(p.PIN_23, Level::Low);
-    let cs = Output::new(p.PIN_25, Level::High);
-    let mut pio = Pio::new(p.PIO0, Irqs);
-    let spi = PioSpi::new(&mut pio.common, pio.sm0, pio.irq0, cs, p.PIN_24, p.PIN_29, p.DMA_CH0);
+static LED_STATES: [Signal<ThreadModeRawMutex, LedStatus>; LED_COUNT] =
+    [const { Signal::new() }; LED_COUNT];
 
+#[embassy_executor::task(pool_size = 2)]
+async fn led_task(
+    mut led: RgbLed<'static>,
+    signal: &'static Signal<ThreadModeRawMutex, LedStatus>,
+) -> ! {
+    led.task(signal).await
+}
 
+#[embassy_executor::task(pool_size = 2)]
+async fn pwm_led_task(
+    mut led: PwmRgbLed<'static>,
+    signal: &'static Signal<ThreadModeRawMutex, LedStatus>,
+) -> ! {
+    led.task(signal).await
+}
 
+static BUTTON_COUNT: usize = 4;
 
+static BUTTON_CHANNEL: ButtonChannel = Channel::new();
 
-    static STATE: StaticCell<cyw43::State> = StaticCell::new();
-    let state = STATE.init(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
-    unwrap!(spawner.spawn(cyw43_task(runner)));
+#[embassy_executor::task(pool_size = 4)]
+async fn button_task(mut button: Button<'static>, id: usize) -> ! {
+    button.task(id, &BUTTON_CHANNEL, Some(&BLE_BUTTONS)).await
+}
 
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
+static INPUT_CHANNEL: InputChannel = Channel::new();
 
-    let mut scanner = 
-    control.scan(ScanOptions::default()).await;
-    while let Some(item) = scanner.next().await {
-        info!("AP: {}", core::str::from_utf8(&item.ssid[0..item.ssid_len as usize]).unwrap());
-    }
+#[embassy_executor::task]
+async fn message_parser_task() -> ! {
+    message_parser(&INPUT_CHANNEL, &LED_SIGNALS, &LED_STATES, Some(&BLE_LED_STATES)).await
+}
 
-    drop(scanner);
-    //spawner.spawn(wifi_task(scanner));
+static SCHEDULE_CHANNEL: InputChannel = Channel::new();
 
-    let config = embassy_net::Config::dhcpv4(Default::default());
+#[embassy_executor::task]
+async fn scheduler_task(storage: &'static SharedStorage) -> ! {
+    Scheduler::load(storage, LED_COUNT, Location::from_env())
+        .await
+        .run(storage, &SCHEDULE_CHANNEL, &INPUT_CHANNEL)
+        .await
+}
 
-    let mut rng = RoscRng;
-    let seed = rng.next_u64();    
-    
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
-    let (stack, runner) = embassy_net::new(net_device, config, RESOURCES.init(StackResources::new()), seed);
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    telemetry::paint_stack();
+    let p = embassy_rp::init(Default::default());
 
-    unwrap!(spawner.spawn(net_task(runner)));
+    // Constants
+    let chip_id_num = embassy_rp::pac::SYSINFO.chip_id().read();
+    let chip_id:heapless::String<12> = {
+        info!("CHIP ID IS : {:x}", chip_id_num.0);
+        let mut chip_id: heapless::String<12> = heapless::String::new();
+        core::fmt::write(&mut chip_id, format_args!("ksl-{:X}", chip_id_num.0));
+        chip_id
+    };
 
+    // Leds
+    #[cfg_attr(feature = "ethernet-w5500", allow(unused_mut))]
+    let mut led1 = RgbLed {
+        red: Output::new(p.PIN_13, Level::High),
+        green: Output::new(p.PIN_12, Level::High),
+        blue: Output::new(p.PIN_11, Level::High),
+    };
+    // a missing radio firmware blinks the red of the first led, which is active low
+    #[cfg(not(feature = "ethernet-w5500"))]
+    board::check_firmware(&mut led1.red, Level::Low, board::BLUETOOTH_FIRMWARE).await;
+
+    let mut c = rp::pwm::Config::default();
+    c.top = 32_768;
+    c.compare_b = 8;
+    c.invert_a = true;
+    c.invert_b = true;
+
+    let (red, _) = rp::pwm::Pwm::new_output_a(p.PWM_SLICE5, p.PIN_10, c.clone()).split();
+    let (blue, green) = rp::pwm::Pwm::new_output_ab(p.PWM_SLICE4, p.PIN_8, p.PIN_9, c).split();
+
+    let led2 = PwmRgbLed {
+        red: red.unwrap(),
+        green: green.unwrap(),
+        blue: blue.unwrap(),
+    };
 
-    control.join(&wifi_ssid, JoinOptions::new(wifi_password)).await.unwrap();
+    unwrap!(spawner.spawn(led_task(led1, &LED_SIGNALS[0])));
+    unwrap!(spawner.spawn(pwm_led_task(led2, &LED_SIGNALS[1])));
+    unwrap!(spawner.spawn(message_parser_task()));
+
+    // Schedules, running from flash even without a broker
+    static STORAGE: StaticCell<SharedStorage> = StaticCell::new();
+    let storage = STORAGE.init(Mutex::new(Storage::new(Flash::new_blocking(p.FLASH))));
+    unwrap!(spawner.spawn(scheduler_task(storage)));
+
+    // Buttons
+    let button1 = Button { input: Input::new(p.PIN_17, Pull::Up) };
+    let button2 = Button { input: Input::new(p.PIN_16, Pull::Up) };
+    let button3 = Button { input: Input::new(p.PIN_15, Pull::Up) };
+    let button4 = Button { input: Input::new(p.PIN_14, Pull::Up) };
+
+    // holding the first button at boot enters provisioning
+    let provisioning_requested = button1.is_pressed();
+    unwrap!(spawner.spawn(button_task(button1, 1)));
+    unwrap!(spawner.spawn(button_task(button2, 2)));
+    unwrap!(spawner.spawn(button_task(button3, 3)));
+    unwrap!(spawner.spawn(button_task(button4, 4)));
+
+    // the access point needs the radio
+    let provisioning = cfg!(not(feature = "ethernet-w5500"))
+        && (provisioning_requested || KnownNetworks::load(storage).await.is_empty());
+    let ip_settings = IpSettings::from_env();
+    let config = if provisioning {
+        provisioning::stack_config()
+    } else {
+        ip_settings.stack_config()
+    };
 
+    #[cfg(not(feature = "ethernet-w5500"))]
+    let (stack, mut control) = {
+        let radio = RadioPeripherals {
+            pwr: p.PIN_23,
+            cs: p.PIN_25,
+            dio: p.PIN_24,
+            clk: p.PIN_29,
+            pio: p.PIO0,
+            dma: p.DMA_CH0,
+        };
+        let (net_device, bt_device, control) = board::init_radio_with_bluetooth(spawner, radio).await;
+        // commissioning over bluetooth while provisioning, and the led service for bonded phones
+        let controller = ExternalController::new(bt_device);
+        let address = ble::address(chip_id_num.0);
+        unwrap!(spawner.spawn(ble_task(controller, address, chip_id.clone(), storage, provisioning)));
+        (board::init_stack(spawner, net_device, config), control)
+    };
 
+    #[cfg(feature = "ethernet-w5500")]
+    let stack = {
+        let pins = EthernetPins {
+            spi: p.SPI0,
+            sck: p.PIN_2,
+            mosi: p.PIN_3,
+            miso: p.PIN_4,
+            cs: p.PIN_5,
+            int: p.PIN_6,
+            reset: p.PIN_7,
+            tx_dma: p.DMA_CH1,
+            rx_dma: p.DMA_CH2,
+        };
+        board::init_ethernet(spawner, pins, ethernet::mac_address(chip_id_num.0), config).await
+    };
 
-    info!("waiting for DHCP...");
-    while !stack.is_config_up() {
-        Timer::after_millis(100).await;
+    #[cfg(not(feature = "ethernet-w5500"))]
+    {
+        if provisioning {
+            provisioning::run(&mut control, stack, storage, &chip_id).await;
+        }
+        static CONTROL: StaticCell<wifi::SharedControl> = StaticCell::new();
+        let control = CONTROL.init(Mutex::new(control));
+        unwrap!(spawner.spawn(wifi_task(control, stack, storage)));
+        unwrap!(spawner.spawn(status_led_task(control)));
     }
-    info!("DHCP is now up!");
+    unwrap!(spawner.spawn(ip_task(stack, ip_settings)));
+    unwrap!(spawner.spawn(sntp_task(stack)));
 
 
     
     let delay = Duration::from_secs(1);
+    let settings = MqttSettings::load(storage).await;
+    let broker_config = &settings.broker;
+    let credentials = &settings.credentials;
+    let prefix = settings.prefix.as_str();
+    // kept across connections, for what the broker did not acknowledge yet
+    let mut outbox: Outbox<8> = Outbox::new();
+    let mut session = SessionState::new();
+    info!("broker : {}", broker_config);
+    let mut groups = Groups::load(storage).await;
+    info!("groups : {}", groups);
+    #[cfg(feature = "tls")]
+    let mut rng = RoscRng;
+    #[cfg(feature = "tls")]
+    let tls_config = if broker_config.host.is_empty() {
+        TlsConfig::new()
+    } else {
+        TlsConfig::new().with_server_name(&broker_config.host)
+    };
+    #[cfg(feature = "tls-client-cert")]
+    let tls_config = tls_config
+        .with_cert(Certificate::X509(CLIENT_CERT))
+        .with_priv_key(CLIENT_KEY);
+    let mut connected_before = false;
     loop {
-      
+        board::wait_link_up(stack).await;
+        board::wait_for_address(stack).await;
+        let mut buffers = MqttBuffers::new();
         let mut tcp_rx_buffer = [0; 1500];
         let mut tcp_tx_buffer = [0; 1500];
-    
+        #[cfg(feature = "tls")]
+        let mut tls_read_buffer = [0; TLS_READ_RECORD_SIZE];
+        #[cfg(feature = "tls")]
+        let mut tls_write_buffer = [0; TLS_WRITE_RECORD_SIZE];
+
+        let endpoint = broker::resolve(stack, broker_config, BROKER_PORT).await;
         let mut socket = TcpSocket::new(stack, &mut tcp_rx_buffer, &mut tcp_tx_buffer);
-        socket.connect(IpEndpoint::new(IpAddress::v4(192, 168, 103, 2), 1883)).await.unwrap();
-
-        let mut config = ClientConfig::new(
-            rust_mqtt::client::client_config::MqttVersion::MQTTv5,
-            CountingRng(20000),
-        );
-
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id(&chip_id);
-
-        config.max_packet_size = 100;
-        let mut recv_buffer = [0; 80];
-        let mut write_buffer = [0; 80];
-        let mut client = MqttClient::<_, 5, _>::new(
-            socket,
-            &mut write_buffer,
-            80,
-            &mut recv_buffer,
-            80,
-            config,
-        );
-
-        client.connect_to_broker().await.unwrap();
-        let mut buff :String<32> = String::new();
-        core::fmt::write(&mut buff, format_args!("{prefix}/{chip_id}/#")).expect("could not write topic, maybe prefix too long");
-        client.subscribe_to_topic(&buff).await.unwrap();
-        buff.clear();
-        core::fmt::write(&mut buff, format_args!("{prefix}/+")).expect("prefix too long");
-        client.subscribe_to_topic(&buff).await.unwrap();
-        loop {
-            let (topic, body) = client.receive_message().await.unwrap();
-            let mut parts = topic.split('/');
-            core::assert_eq!(Some(prefix),parts.next());
-            match parts.next(){
-                Some("time") => {}
-                Some("local_time") => {}
-                Some(x) if x == chip_id => {
-                    match parts.next(){
-                        Some("led") => 
+        if let Err(e) = socket.connect(endpoint).await {
+            log_warn!("could not connect to broker : {:?}", e);
+            Timer::after(delay).await;
+            continue;
+        }
+
+        #[cfg(feature = "tls")]
+        let connection = {
+            let mut connection = TlsConnection::new(socket, &mut tls_read_buffer, &mut tls_write_buffer);
+            let provider = PinnedProvider::new(tls_rng(&mut rng), SERVER_FINGERPRINT);
+            if let Err(e) = connection.open(TlsContext::new(&tls_config, provider)).await {
+                log_warn!("tls handshake failed : {:?}", e);
+                Timer::after(delay).await;
+                continue;
+            }
+            connection
+        };
+        #[cfg(not(feature = "tls"))]
+        let connection = socket;
+
+        let device = Device {
+            chip_id: &chip_id,
+            prefix,
+            stack,
+            storage,
+            credentials,
+            broker: broker_config,
+        };
+        if connected_before {
+            telemetry::record_reconnect();
+        }
+        connected_before = true;
+        if let Err(e) = mqtt_session(connection, &mut buffers, &mut session, &mut outbox, &mut groups, &device).await {
+            log_warn!("mqtt session ended : {:?}", e);
+        }
+        Timer::after(delay).await;
+    }
+}
+
+/// What a session needs to know about the device.
+#[derive(Clone, Copy)]
+struct Device<'a> {
+    chip_id: &'a str,
+    prefix: &'a str,
+    stack: Stack<'static>,
+    storage: &'static SharedStorage,
+    credentials: &'a MqttCredentials,
+    broker: &'a BrokerConfig,
+}
+
+async fn mqtt_session<T: Read + Write>(
+    connection: T,
+    buffers: &mut MqttBuffers,
+    session: &mut SessionState,
+    outbox: &mut Outbox<8>,
+    groups: &mut Groups,
+    device: &Device<'_>,
+) -> Result<(), MqttError> {
+    let Device {
+        chip_id,
+        prefix,
+        storage,
+        credentials,
+        ..
+    } = *device;
+    let options = ConnectOptions {
+        client_id: chip_id,
+        username: (!credentials.username.is_empty()).then_some(credentials.username.as_str()),
+        password: (!credentials.password.is_empty()).then(|| credentials.password.expose()),
+    };
+    let read_started = Cell::new(false);
+    let mut client = MqttClient::new(
+        TrackedConnection::new(connection, &read_started),
+        &mut buffers.send,
+        &mut buffers.recv,
+        session,
+    );
+
+    let session_present = client.connect(&options).await?;
+    let mut filters: Vec<String<64>, { COMMAND_SUBTOPICS.len() + 2 }> = Vec::new();
+    for subtopic in COMMAND_SUBTOPICS {
+        let mut filter = String::new();
+        core::fmt::write(&mut filter, format_args!("{prefix}/{chip_id}/{subtopic}"))
+            .expect("could not write topic, maybe prefix too long");
+        let _ = filters.push(filter);
+    }
+    for filter in ["+", "all/#"] {
+        let mut buff: String<64> = String::new();
+        core::fmt::write(&mut buff, format_args!("{prefix}/{filter}")).expect("prefix too long");
+        let _ = filters.push(buff);
+    }
+    let filters: Vec<&str, { COMMAND_SUBTOPICS.len() + 2 }> = filters.iter().map(String::as_str).collect();
+    client.subscribe(&filters).await?;
+    for group in groups.iter() {
+        client.subscribe(&[group_filter(prefix, group).as_str()]).await?;
+    }
+
+    // retained and republished on every connection, so not worth waiting for acknowledgements
+    let mut discovery_topic: String<96> = String::new();
+    let mut discovery_config: String<480> = String::new();
+    for led in 1..=LED_COUNT {
+        discovery_topic.clear();
+        discovery_config.clear();
+        discovery::light_topic(&mut discovery_topic, chip_id, led).expect("topic too long");
+        discovery::light_config(&mut discovery_config, prefix, chip_id, led)
+            .expect("light config too long");
+        match publish(&mut client, &discovery_topic, discovery_config.as_bytes(), true).await {
+            Ok(()) => {}
+            Err(PublishError::TooLarge(e)) => warn!("discovery for led {} skipped : {}", led, e),
+            Err(PublishError::Mqtt(e)) => return Err(e),
+        }
+    }
+    for button in 1..=BUTTON_COUNT {
+        for event in ButtonEvent::ALL {
+            discovery_topic.clear();
+            discovery_config.clear();
+            discovery::button_trigger_topic(&mut discovery_topic, chip_id, button, event)
+                .expect("topic too long");
+            discovery::button_trigger_config(&mut discovery_config, prefix, chip_id, button, event)
+                .expect("trigger config too long");
+            match publish(&mut client, &discovery_topic, discovery_config.as_bytes(), true).await {
+                Ok(()) => {}
+                Err(PublishError::TooLarge(e)) => warn!("discovery for button {} skipped : {}", button, e),
+                Err(PublishError::Mqtt(e)) => return Err(e),
+            }
+        }
+    }
+
+    // what could not be delivered during the previous connection
+    if !outbox.is_empty() {
+        info!("retrying {} unacknowledged messages", outbox.len());
+    }
+    resume_outbox(&mut client, outbox, session_present).await?;
+
+    let mut telemetry_topic: String<64> = String::new();
+    core::fmt::write(&mut telemetry_topic, format_args!("{prefix}/{chip_id}/telemetry"))
+        .expect("prefix too long");
+    publish_telemetry(&mut client, &telemetry_topic, device.stack).await?;
+    let mut telemetry_ticker = Ticker::every(TELEMETRY_INTERVAL);
+    let mut log_topic: String<64> = String::new();
+    core::fmt::write(&mut log_topic, format_args!("{prefix}/{chip_id}/log")).expect("prefix too long");
+    // an event which came while a packet was half read, handled once it is received
+    let mut deferred = None;
+    loop {
+        let event = match deferred.take() {
+            Some(event) => Either::Second(event),
+            None => {
+                read_started.set(false);
+                let mut receive = pin!(client.receive());
+                let events = select3(
+                    select_array(LED_STATES.each_ref().map(|state| state.wait())),
+                    select(BUTTON_CHANNEL.receive(), LOG_CHANNEL.receive()),
+                    select(telemetry_ticker.next(), board::wait_link_down(device.stack)),
+                );
+                match select(receive.as_mut(), events).await {
+                    Either::First(received) => Either::First(received),
+                    Either::Second(event) if read_started.get() => {
+                        deferred = Some(event);
+                        Either::First(receive.await)
                     }
+                    Either::Second(event) => Either::Second(event),
                 }
-                Some(y) => {
-                    debug!("unknown {}", y)
+            }
+        };
+        let (topic, body) = match event {
+            Either::First(Ok(Event::Message(message))) => (message.topic, message.payload),
+            Either::First(Ok(Event::Released(packet_id))) => {
+                outbox.released(packet_id);
+                continue;
+            }
+            Either::First(Ok(Event::Delivered(packet_id))) => {
+                outbox.completed(packet_id);
+                flush_outbox(&mut client, outbox).await?;
+                continue;
+            }
+            Either::First(Ok(Event::Refused(packet_id, reason))) => {
+                if let Some(entry) = outbox.completed(packet_id) {
+                    log_warn!("message {} on {} refused : {:x}", entry.id, entry.topic.as_str(), reason);
                 }
-                None => {
-                    warn!("no second arg")
+                flush_outbox(&mut client, outbox).await?;
+                continue;
+            }
+            Either::First(Err(MqttError::BufferTooSmall)) => {
+                log_error!("incoming packet exceeds the {} bytes receive buffer", RECV_BUFFER_SIZE);
+                return Err(MqttError::BufferTooSmall);
+            }
+            Either::First(Err(e)) => return Err(e),
+            Either::Second(Either3::Third(Either::Second(()))) => {
+                log_warn!("network lost, ending mqtt session");
+                return Err(MqttError::Network(ErrorKind::NotConnected));
+            }
+            Either::Second(Either3::Third(Either::First(()))) => {
+                publish_telemetry(&mut client, &telemetry_topic, device.stack).await?;
+                continue;
+            }
+            Either::Second(Either3::Second(Either::Second(record))) => {
+                let mut payload = [0; 256];
+                match record.write(&mut payload) {
+                    Ok(len) => match publish(&mut client, &log_topic, &payload[..len], false).await {
+                        Ok(()) => {}
+                        Err(PublishError::TooLarge(e)) => warn!("log record too large : {}", e),
+                        Err(PublishError::Mqtt(e)) => return Err(e),
+                    },
+                    Err(_) => warn!("log record does not fit"),
+                }
+                continue;
+            }
+            Either::Second(Either3::Second(Either::First((button, event)))) => {
+                power::record_activity();
+                let mut button_topic: String<64> = String::new();
+                core::fmt::write(
+                    &mut button_topic,
+                    format_args!("{prefix}/{chip_id}/button/{button}"),
+                )
+                .expect("prefix too long");
+                // a press must be neither lost nor applied twice
+                if let Err(e) = outbox.push(&button_topic, event.as_str().as_bytes(), Qos::ExactlyOnce, false) {
+                    log_warn!("button event dropped : {:?}", e);
                 }
+                flush_outbox(&mut client, outbox).await?;
+                continue;
             }
-            client
-                .send_message(
-                    "hello",
-                    b"hello2",
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                    true,
+            Either::Second(Either3::First((status, index))) => {
+                let mut state_topic: String<64> = String::new();
+                let mut payload = [0; MAX_JSON_PAYLOAD];
+                core::fmt::write(
+                    &mut state_topic,
+                    format_args!("{prefix}/{chip_id}/led/{}/state", index + 1),
                 )
-                .await
-                .unwrap();
-            Timer::after(Duration::from_secs(500)).await;
+                .expect("prefix too long");
+                let len = LedState::new(&status)
+                    .write(&mut payload)
+                    .expect("led state does not fit payload");
+                // retained, so a lost update is replaced by the next one
+                match publish(&mut client, &state_topic, &payload[..len], true).await {
+                    Ok(()) => {}
+                    Err(PublishError::TooLarge(e)) => warn!("led state too large : {}", e),
+                    Err(PublishError::Mqtt(e)) => {
+                        // keep it for the next connection
+                        LED_STATES[index].signal(status);
+                        return Err(e);
+                    }
+                }
+                continue;
+            }
+        };
+        let mut parts = topic.splitn(3, '/');
+        if parts.next() != Some(prefix) {
+            log_warn!("topic outside of prefix : {}", topic);
+            continue;
+        }
+        match parts.next(){
+            Some("time") => clock::on_time_message(body),
+            Some("local_time") => clock::on_local_time_message(body),
+            Some(x) if x == chip_id => {
+                let subtopic = parts.next().unwrap_or_default();
+                power::record_activity();
+                if subtopic == "power" {
+                    if !power::on_profile_message(body) {
+                        log_warn!("invalid power profile");
+                        telemetry::record_parse_error();
+                    }
+                    continue;
+                }
+                if subtopic == "log/level" {
+                    if !logging::on_level_message(body) {
+                        log_warn!("invalid log level");
+                        telemetry::record_parse_error();
+                    }
+                    continue;
+                }
+                if let Some(name) = subtopic.strip_prefix("cmd/") {
+                    match Call::new(prefix, chip_id, name, body) {
+                        Some(call) => handle_command(&mut client, call, device, outbox, groups).await?,
+                        None => log_warn!("command on {} too long to answer", topic),
+                    }
+                    continue;
+                }
+                if subtopic == "groups" {
+                    let next = match core::str::from_utf8(body).map(Groups::parse) {
+                        Ok(Ok(next)) => next,
+                        Ok(Err(e)) => {
+                            log_warn!("invalid groups : {:?}", e);
+                            telemetry::record_parse_error();
+                            continue;
+                        }
+                        Err(_) => {
+                            log_warn!("groups are not utf8");
+                            telemetry::record_parse_error();
+                            continue;
+                        }
+                    };
+                    if next == *groups {
+                        continue;
+                    }
+                    for group in groups.iter().filter(|group| !next.contains(group)) {
+                        client.unsubscribe(&[group_filter(prefix, group).as_str()]).await?;
+                    }
+                    for group in next.iter().filter(|group| !groups.contains(group)) {
+                        client.subscribe(&[group_filter(prefix, group).as_str()]).await?;
+                    }
+                    info!("groups : {}", next);
+                    if let Err(e) = next.save(storage).await {
+                        log_error!("could not save groups : {:?}", e);
+                    }
+                    *groups = next;
+                    continue;
+                }
+                let channel = if subtopic.starts_with("schedule/") {
+                    &SCHEDULE_CHANNEL
+                } else {
+                    &INPUT_CHANNEL
+                };
+                match ChannelMessage::from_mqtt(subtopic, body) {
+                    Ok(message) => channel.send(message).await,
+                    Err(e) => {
+                        log_warn!("dropping message on {} : {:?}", topic, e);
+                        telemetry::record_parse_error();
+                    }
+                }
+            }
+            // fleet wide commands only reach the leds
+            Some("all") => forward_led_command(topic, parts.next().unwrap_or_default(), body).await,
+            Some("group") => match parts.next().unwrap_or_default().split_once('/') {
+                Some((group, subtopic)) if groups.contains(group) => {
+                    forward_led_command(topic, subtopic, body).await
+                }
+                _ => debug!("not a member of the group on {}", topic),
+            },
+            Some(y) => {
+                debug!("unknown {}", y)
+            }
+            None => {
+                warn!("no second arg")
+            }
+        }
+    }
+}
+
+/// Subtopics of `{prefix}/{chip_id}/` the device acts on. What it publishes there itself, its
+/// led states, button events, telemetry, logs and command replies, is left out so the broker
+/// does not send it back.
+const COMMAND_SUBTOPICS: [&str; 10] = [
+    "led/+/color",
+    "led/+/power",
+    "led/+/anim",
+    "led/+/set",
+    "identify",
+    "power",
+    "log/level",
+    "cmd/+",
+    "groups",
+    "schedule/+",
+];
+
+/// Subscription for the messages to `group`.
+fn group_filter(prefix: &str, group: &str) -> String<64> {
+    let mut filter = String::new();
+    core::fmt::write(&mut filter, format_args!("{prefix}/group/{group}/#"))
+        .expect("prefix too long");
+    filter
+}
+
+/// Hands a broadcast or group `led/...` subtopic to the message parser.
+async fn forward_led_command(topic: &str, subtopic: &str, body: &[u8]) {
+    if !subtopic.starts_with("led/") || subtopic.ends_with("/state") {
+        debug!("ignoring {}", topic);
+        return;
+    }
+    power::record_activity();
+    match ChannelMessage::from_mqtt(subtopic, body) {
+        Ok(message) => INPUT_CHANNEL.send(message).await,
+        Err(e) => {
+            log_warn!("dropping message on {} : {:?}", topic, e);
+            telemetry::record_parse_error();
+        }
+    }
+}
+
+/// Runs a command received on `cmd/<name>` and publishes its reply.
+async fn handle_command<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    call: Call,
+    device: &Device<'_>,
+    outbox: &Outbox<8>,
+    groups: &Groups,
+) -> Result<(), MqttError> {
+    info!("command {}", call.name.as_str());
+    let mut payload = [0; 512];
+    let reply = match call.command {
+        _ if call.invalid => {
+            telemetry::record_parse_error();
+            call.reply::<()>(Status::InvalidRequest, None, &mut payload)
+        }
+        None => call.reply::<()>(Status::UnknownCommand, None, &mut payload),
+        Some(Command::Reboot) | Some(Command::Identify) => {
+            call.reply::<()>(Status::Ok, None, &mut payload)
+        }
+        Some(Command::Config) => {
+            let mut address: String<48> = String::new();
+            if let Some(config) = device.stack.config_v4() {
+                let _ = core::fmt::write(&mut address, format_args!("{}", config.address));
+            }
+            let mut group_list: String<{ mqtt_pico::config::MAX_GROUPS * 17 }> = String::new();
+            let _ = groups.write(&mut group_list);
+            let broker_host = if device.broker.host.is_empty() {
+                broker::MDNS_SERVICE
+            } else {
+                &device.broker.host
+            };
+            let report = rpc::ConfigReport {
+                version: env!("CARGO_PKG_VERSION"),
+                chip_id: device.chip_id,
+                address: &address,
+                broker: broker_host,
+                leds: LED_COUNT,
+                buttons: BUTTON_COUNT,
+                groups: &group_list,
+                uptime_s: Instant::now().as_secs(),
+                time_source: clock::source().map(|source| match source {
+                    clock::TimeSource::Mqtt => "mqtt",
+                    clock::TimeSource::Sntp => "sntp",
+                }),
+                // no radio power management over ethernet
+                power: (!cfg!(feature = "ethernet-w5500")).then(|| power::profile().as_str()),
+            };
+            call.reply(Status::Ok, Some(report), &mut payload)
+        }
+        // no radio to scan with
+        Some(Command::Scan) if cfg!(feature = "ethernet-w5500") => {
+            call.reply::<()>(Status::Failed, None, &mut payload)
+        }
+        Some(Command::Scan) => {
+            wifi::SCAN_RESULTS.reset();
+            wifi::SCAN_REQUEST.signal(());
+            let networks = wifi::SCAN_RESULTS.wait().await;
+            let mut entries: Vec<rpc::ScanEntry, { wifi::MAX_SCAN_RESULTS }> = Vec::new();
+            for network in networks.iter() {
+                let _ = entries.push(rpc::ScanEntry {
+                    ssid: &network.ssid,
+                    rssi: network.rssi,
+                    channel: network.channel,
+                });
+            }
+            call.reply(Status::Ok, Some(&entries[..]), &mut payload)
+        }
+        Some(Command::SelfTest) => {
+            let report = rpc::SelfTestReport {
+                clock: clock::is_synchronized(),
+                network: device.stack.is_config_up(),
+                outbox_free: 8 - outbox.len(),
+            };
+            let status = if report.clock && report.network {
+                Status::Ok
+            } else {
+                Status::Failed
+            };
+            // the leds are checked by eye
+            identify(rpc::DEFAULT_IDENTIFY_SECONDS).await;
+            call.reply(status, Some(report), &mut payload)
+        }
+    };
+    match reply {
+        Ok(len) => match publish(client, &call.response_topic, &payload[..len], false).await {
+            Ok(()) => {}
+            Err(PublishError::TooLarge(e)) => warn!("reply to {} too large : {}", call.name.as_str(), e),
+            Err(PublishError::Mqtt(e)) => return Err(e),
+        },
+        Err(_) => warn!("reply to {} does not fit", call.name.as_str()),
+    }
+    match call.command {
+        Some(Command::Identify) if !call.invalid => {
+            identify(call.seconds.unwrap_or(rpc::DEFAULT_IDENTIFY_SECONDS)).await
+        }
+        Some(Command::Reboot) if !call.invalid => {
+            info!("rebooting");
+            // let the reply reach the broker
+            Timer::after_millis(500).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Has the message parser flash every led.
+async fn identify(seconds: u8) {
+    let mut duration: String<4> = String::new();
+    let _ = core::fmt::write(&mut duration, format_args!("{seconds}"));
+    match ChannelMessage::new("identify", "", "", &duration) {
+        Ok(message) => INPUT_CHANNEL.send(message).await,
+        Err(e) => warn!("could not identify : {}", e),
+    }
+}
+
+async fn publish_telemetry<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    topic: &str,
+    stack: Stack<'static>,
+) -> Result<(), MqttError> {
+    let mut address = String::new();
+    let mut payload = [0; 256];
+    let len = match Telemetry::collect(stack, &mut address).write(&mut payload) {
+        Ok(len) => len,
+        Err(_) => {
+            warn!("telemetry does not fit");
+            return Ok(());
+        }
+    };
+    match publish(client, topic, &payload[..len], false).await {
+        Ok(()) => Ok(()),
+        Err(PublishError::TooLarge(e)) => {
+            warn!("telemetry too large : {}", e);
+            Ok(())
         }
+        Err(PublishError::Mqtt(e)) => Err(e),
     }
 }
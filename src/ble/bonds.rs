@@ -0,0 +1,82 @@
+//! Phones bonded over LE Secure Connections, kept in flash so they can still use the
+//! [`leds`](super::leds) service of a commissioned device after a reboot.
+//!
+//! Bonds are only stored in provisioning mode : a phone pairing with a commissioned device gets
+//! an encrypted link, but is not trusted, see [`super`].
+
+use heapless::Vec;
+use trouble_host::prelude::*;
+
+use crate::storage::{SharedStorage, Slot, StorageError};
+
+pub const MAX_BONDS: usize = 4;
+/// Identity address, whether an IRK follows, the IRK and the LTK.
+const BOND_SIZE: usize = 6 + 1 + 16 + 16;
+
+#[derive(Default)]
+pub struct Bonds(Vec<BondInformation, MAX_BONDS>);
+
+impl Bonds {
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut bonds = Self::default();
+        for record in data.chunks(BOND_SIZE) {
+            let (address, rest) = record.split_first_chunk::<6>()?;
+            let (&has_irk, rest) = rest.split_first()?;
+            let (irk, rest) = rest.split_first_chunk::<16>()?;
+            let ltk = rest.first_chunk::<16>()?;
+            let identity = Identity {
+                bd_addr: BdAddr::new(*address),
+                irk: (has_irk != 0).then(|| IdentityResolvingKey(u128::from_le_bytes(*irk))),
+            };
+            let bond = BondInformation::new(identity, LongTermKey(u128::from_le_bytes(*ltk)));
+            bonds.0.push(bond).ok()?;
+        }
+        Some(bonds)
+    }
+
+    /// The bonds saved by [`Bonds::save`], none if there are none or they are corrupt.
+    pub async fn load(storage: &SharedStorage) -> Self {
+        let mut buffer = [0; MAX_BONDS * BOND_SIZE];
+        match storage.lock().await.read(Slot::Bonds, &mut buffer).map(Self::decode) {
+            Some(Some(bonds)) => bonds,
+            Some(None) => {
+                defmt::warn!("invalid saved bonds");
+                Self::default()
+            }
+            None => Self::default(),
+        }
+    }
+
+    pub async fn save(&self, storage: &SharedStorage) -> Result<(), StorageError> {
+        let mut data: Vec<u8, { MAX_BONDS * BOND_SIZE }> = Vec::new();
+        for bond in &self.0 {
+            let irk = bond.identity.irk.map_or(0, |irk| irk.0);
+            // sized for MAX_BONDS
+            let _ = data.extend_from_slice(bond.identity.bd_addr.raw());
+            let _ = data.push(u8::from(bond.identity.irk.is_some()));
+            let _ = data.extend_from_slice(&irk.to_le_bytes());
+            let _ = data.extend_from_slice(&bond.ltk.0.to_le_bytes());
+        }
+        storage.lock().await.write(Slot::Bonds, &data)
+    }
+
+    /// Remembers `bond`, replacing an earlier bond of the same phone, or the oldest one when
+    /// full.
+    pub fn add(&mut self, bond: BondInformation) {
+        self.0
+            .retain(|known| known.identity.bd_addr != bond.identity.bd_addr);
+        if self.0.is_full() {
+            self.0.remove(0);
+        }
+        let _ = self.0.push(bond);
+    }
+
+    /// Whether the peer at `address` is a bonded phone, resolving its private address.
+    pub fn contains(&self, address: &BdAddr) -> bool {
+        self.0.iter().any(|bond| bond.identity.match_address(address))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BondInformation> {
+        self.0.iter()
+    }
+}
@@ -0,0 +1,113 @@
+//! Remote control service, for using the device from a phone without Wi-Fi or broker.
+//!
+//! Writes to `command` take the part of an MQTT topic following `{prefix}/{chip_id}/`, a space,
+//! then the MQTT payload, e.g. `led/1/color #f00`, `led/2/set {"state":"ON"}` or
+//! `identify 10`, and go through the same parser as MQTT messages. While connected, led
+//! changes are notified on `led_state` as `led/1/state {"state":"ON",...}` and button events
+//! on `button` as `button/1 press`.
+//!
+//! The service needs an encrypted link, with a bonded phone on a commissioned device, see
+//! [`super`].
+
+use core::fmt::Write;
+
+use defmt::*;
+use heapless::{String, Vec};
+use trouble_host::prelude::*;
+
+use super::Server;
+use crate::input::buttons::{ButtonChannel, ButtonEvent};
+use crate::input::messages::{ChannelMessage, InputChannel, LedStateChannel};
+use crate::log_warn;
+use crate::mqtt::json::{LedState, MAX_JSON_PAYLOAD};
+use crate::output::leds::LedStatus;
+use crate::telemetry;
+
+/// Longest subtopic, separator and payload.
+pub const MAX_COMMAND: usize = 32 + MAX_JSON_PAYLOAD;
+
+#[gatt_service(uuid = "6e7f2000-7d43-4c5c-9a4e-2b1f0e9f3a01")]
+pub struct LedService {
+    #[characteristic(uuid = "6e7f2001-7d43-4c5c-9a4e-2b1f0e9f3a01", write)]
+    pub command: Vec<u8, MAX_COMMAND>,
+    #[characteristic(uuid = "6e7f2002-7d43-4c5c-9a4e-2b1f0e9f3a01", read, notify)]
+    pub led_state: Vec<u8, MAX_COMMAND>,
+    #[characteristic(uuid = "6e7f2003-7d43-4c5c-9a4e-2b1f0e9f3a01", read, notify)]
+    pub button: String<32>,
+}
+
+/// Where commands go, and where led changes and button events come from.
+pub struct Channels {
+    pub input: &'static InputChannel,
+    pub states: &'static LedStateChannel,
+    pub buttons: &'static ButtonChannel,
+}
+
+impl Channels {
+    /// Forgets what happened while nobody was connected.
+    pub fn clear(&self) {
+        self.states.clear();
+        self.buttons.clear();
+    }
+}
+
+/// Whether `handle` is one of the characteristics of the service.
+pub fn is_led_handle(server: &Server<'_>, handle: u16) -> bool {
+    let leds = &server.leds;
+    [leds.command.handle, leds.led_state.handle, leds.button.handle].contains(&handle)
+}
+
+/// Handles a write to `command`.
+pub async fn on_write(server: &Server<'_>, handle: u16, data: &[u8], channels: &Channels) {
+    if handle != server.leds.command.handle {
+        return;
+    }
+    let (subtopic, payload) = match core::str::from_utf8(data) {
+        Ok(text) => text.split_once(' ').unwrap_or((text, "")),
+        Err(_) => {
+            log_warn!("ble command is not utf8");
+            telemetry::record_parse_error();
+            return;
+        }
+    };
+    match ChannelMessage::from_mqtt(subtopic, payload.as_bytes()) {
+        Ok(message) => channels.input.send(message).await,
+        Err(e) => {
+            log_warn!("dropping ble command {} : {:?}", subtopic, e);
+            telemetry::record_parse_error();
+        }
+    }
+}
+
+pub async fn notify_led(
+    server: &Server<'_>,
+    connection: &GattConnection<'_, '_>,
+    id: usize,
+    status: LedStatus,
+) {
+    let mut value: Vec<u8, MAX_COMMAND> = Vec::new();
+    let mut head: String<16> = String::new();
+    let _ = write!(head, "led/{id}/state ");
+    let _ = value.extend_from_slice(head.as_bytes());
+    let start = value.len();
+    // the payload buffer is as large as the json states
+    unwrap!(value.resize_default(start + MAX_JSON_PAYLOAD));
+    let len = unwrap!(LedState::new(&status).write(&mut value[start..]).ok());
+    value.truncate(start + len);
+    if let Err(e) = server.leds.led_state.notify(connection, &value).await {
+        warn!("ble notify failed : {:?}", e);
+    }
+}
+
+pub async fn notify_button(
+    server: &Server<'_>,
+    connection: &GattConnection<'_, '_>,
+    id: usize,
+    event: ButtonEvent,
+) {
+    let mut value: String<32> = String::new();
+    let _ = write!(value, "button/{id} {event}");
+    if let Err(e) = server.leds.button.notify(connection, &value).await {
+        warn!("ble notify failed : {:?}", e);
+    }
+}
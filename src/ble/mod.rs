@@ -0,0 +1,274 @@
+//! Bluetooth LE GATT server, advertised as `mqtt-pico-<chip id>`, for commissioning the
+//! device from a phone, see [`provisioning`], and controlling it without a broker, see
+//! [`leds`].
+//!
+//! The commissioning writes are only accepted in provisioning mode, entered by holding the
+//! first button at boot or when no Wi-Fi network is known, where any phone in range may write
+//! them. The led service needs a link encrypted with LE Secure Connections: phones pair, and
+//! bond, with the device in provisioning mode, see [`bonds`], and only bonded phones may use it
+//! on a commissioned device. Its reads and writes are refused with an insufficient encryption
+//! or authentication error otherwise, which makes the phone pair, and nothing is notified.
+
+pub mod bonds;
+pub mod leds;
+pub mod provisioning;
+
+use defmt::*;
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_rp::clocks::RoscRng;
+use heapless::String;
+use rand_chacha::ChaCha8Rng;
+use rand_core::{RngCore, SeedableRng};
+use trouble_host::prelude::*;
+
+use crate::log_warn;
+use crate::net::wifi;
+use crate::storage::SharedStorage;
+use bonds::Bonds;
+
+const CONNECTIONS_MAX: usize = 1;
+/// Signaling and attribute protocol channels.
+const L2CAP_CHANNELS_MAX: usize = 2;
+/// Large enough for a whole JSON settings write once the MTU is negotiated.
+const L2CAP_MTU: usize = 251;
+
+#[gatt_server]
+pub struct Server {
+    pub provisioning: provisioning::ProvisioningService,
+    pub leds: leds::LedService,
+}
+
+/// Static random address derived from the chip id, stable across reboots.
+pub fn address(chip_id: u32) -> Address {
+    let [a, b, c, d] = chip_id.to_le_bytes();
+    // the two most significant bits are set in static random addresses
+    Address::random([a, b, c, d, 0x50, 0xd0])
+}
+
+async fn advertise<'a, 'b, C: Controller>(
+    name: &'a str,
+    peripheral: &mut Peripheral<'a, C>,
+    server: &'b Server<'_>,
+) -> Result<GattConnection<'a, 'b>, BleHostError<C::Error>> {
+    let mut advertiser_data = [0; 31];
+    AdStructure::encode_slice(
+        &[
+            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
+            AdStructure::CompleteLocalName(name.as_bytes()),
+        ],
+        &mut advertiser_data[..],
+    )?;
+    let advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &advertiser_data[..],
+                scan_data: &[],
+            },
+        )
+        .await?;
+    info!("ble advertising as {}", name);
+    let connection = advertiser.accept().await?.with_attribute_server(server)?;
+    Ok(connection)
+}
+
+/// What the peer of a connection may do.
+struct Access<'a> {
+    provisioning: bool,
+    bonds: &'a mut Bonds,
+}
+
+impl Access<'_> {
+    /// Whether the peer may use the led service : over an encrypted link, and bonded on a
+    /// commissioned device.
+    fn check(&self, connection: &GattConnection<'_, '_>) -> Result<(), AttErrorCode> {
+        let encrypted = connection
+            .raw()
+            .security_level()
+            .is_ok_and(|level| level.encrypted());
+        if !encrypted {
+            Err(AttErrorCode::INSUFFICIENT_ENCRYPTION)
+        } else if self.provisioning || self.bonds.contains(&connection.raw().peer_address()) {
+            Ok(())
+        } else {
+            Err(AttErrorCode::INSUFFICIENT_AUTHENTICATION)
+        }
+    }
+
+    /// Stores the bond of a phone which just paired, in provisioning mode only.
+    async fn on_bond(&mut self, bond: BondInformation, storage: &SharedStorage) {
+        if !self.provisioning {
+            log_warn!("ble bond of an unknown phone ignored");
+            return;
+        }
+        self.bonds.add(bond);
+        match self.bonds.save(storage).await {
+            Ok(()) => info!("ble bond stored"),
+            Err(e) => log_warn!("ble bond not stored : {:?}", e),
+        }
+    }
+}
+
+/// Serves a connection until the peer disconnects.
+async fn serve(
+    server: &Server<'_>,
+    connection: &GattConnection<'_, '_>,
+    storage: &SharedStorage,
+    channels: &leds::Channels,
+    access: &mut Access<'_>,
+) {
+    let mut wifi_states = unwrap!(wifi::WIFI_STATE.receiver(), "too many wifi state receivers");
+    channels.clear();
+    loop {
+        let event = match select4(
+            connection.next(),
+            wifi_states.changed(),
+            channels.states.receive(),
+            channels.buttons.receive(),
+        )
+        .await
+        {
+            Either4::First(event) => event,
+            Either4::Second(state) => {
+                provisioning::notify_state(server, connection, state).await;
+                continue;
+            }
+            Either4::Third((id, status)) => {
+                if access.check(connection).is_ok() {
+                    leds::notify_led(server, connection, id, status).await;
+                }
+                continue;
+            }
+            Either4::Fourth((id, event)) => {
+                if access.check(connection).is_ok() {
+                    leds::notify_button(server, connection, id, event).await;
+                }
+                continue;
+            }
+        };
+        match event {
+            GattConnectionEvent::Disconnected { reason } => {
+                info!("ble disconnected : {:?}", reason);
+                return;
+            }
+            GattConnectionEvent::PairingComplete {
+                security_level,
+                bond,
+            } => {
+                info!("ble paired : {:?}", security_level);
+                if let Some(bond) = bond {
+                    access.on_bond(bond, storage).await;
+                }
+            }
+            GattConnectionEvent::PairingFailed(e) => warn!("ble pairing failed : {:?}", e),
+            GattConnectionEvent::Gatt { event: Ok(event) } => {
+                let denied = match &event {
+                    GattEvent::Read(read) if leds::is_led_handle(server, read.handle()) => {
+                        access.check(connection).err()
+                    }
+                    GattEvent::Write(write) if leds::is_led_handle(server, write.handle()) => {
+                        access.check(connection).err()
+                    }
+                    GattEvent::Write(write) if provisioning::is_provisioning_handle(server, write.handle()) => {
+                        (!access.provisioning).then_some(AttErrorCode::WRITE_NOT_PERMITTED)
+                    }
+                    _ => None,
+                };
+                if let (None, GattEvent::Write(write)) = (denied, &event) {
+                    provisioning::on_write(server, connection, write.handle(), write.data(), storage)
+                        .await;
+                    leds::on_write(server, write.handle(), write.data(), channels).await;
+                }
+                let reply = match denied {
+                    None => event.accept(),
+                    Some(code) => event.reject(code),
+                };
+                match reply {
+                    Ok(reply) => reply.send().await,
+                    Err(e) => warn!("ble reply failed : {:?}", e),
+                }
+            }
+            GattConnectionEvent::Gatt { event: Err(e) } => warn!("ble gatt error : {:?}", e),
+            _ => {}
+        }
+    }
+}
+
+/// Runs the GATT server on `controller`, advertising again after each disconnection.
+/// `provisioning` accepts the commissioning writes and new bonds.
+pub async fn run<C: Controller>(
+    controller: C,
+    address: Address,
+    chip_id: &str,
+    storage: &SharedStorage,
+    channels: leds::Channels,
+    provisioning: bool,
+) -> ! {
+    let mut name: String<32> = String::new();
+    let _ = core::fmt::write(
+        &mut name,
+        format_args!("mqtt-pico-{}", chip_id.trim_start_matches("ksl-")),
+    );
+    let mut resources: HostResources<CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU> =
+        HostResources::new();
+    // `RoscRng` is not a `CryptoRng`, the pairing keys come from a ChaCha stream seeded by it
+    let mut seed = [0; 32];
+    RoscRng.fill_bytes(&mut seed);
+    let mut rng = ChaCha8Rng::from_seed(seed);
+    let stack = trouble_host::new(controller, &mut resources)
+        .set_random_address(address)
+        .set_random_generator_seed(&mut rng);
+    let mut bonds = Bonds::load(storage).await;
+    for bond in bonds.iter() {
+        if let Err(e) = stack.add_bond_information(bond.clone()) {
+            warn!("ble bond not restored : {:?}", e);
+        }
+    }
+    let Host {
+        mut peripheral,
+        mut runner,
+        ..
+    } = stack.build();
+    let server = unwrap!(Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+        name: &name,
+        appearance: &appearance::UNKNOWN,
+    })));
+    provisioning::init(&server, storage).await;
+
+    let mut access = Access {
+        provisioning,
+        bonds: &mut bonds,
+    };
+    match select(
+        host(&mut runner),
+        advertising(&name, &mut peripheral, &server, storage, &channels, &mut access),
+    )
+    .await
+    {
+        Either::First(never) | Either::Second(never) => never,
+    }
+}
+
+async fn host<C: Controller>(runner: &mut Runner<'_, C>) -> ! {
+    loop {
+        if let Err(e) = runner.run().await {
+            error!("ble host failed : {:?}", e);
+        }
+    }
+}
+
+async fn advertising<'a, C: Controller>(
+    name: &'a str,
+    peripheral: &mut Peripheral<'a, C>,
+    server: &Server<'_>,
+    storage: &SharedStorage,
+    channels: &leds::Channels,
+    access: &mut Access<'_>,
+) -> ! {
+    loop {
+        match advertise(name, peripheral, server).await {
+            Ok(connection) => serve(server, &connection, storage, channels, access).await,
+            Err(e) => warn!("ble advertising failed : {:?}", e),
+        }
+    }
+}
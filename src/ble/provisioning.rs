@@ -0,0 +1,181 @@
+//! Commissioning service : JSON writes to the `wifi` and `mqtt` characteristics store a network
+//! and the broker settings, as the provisioning web page does, and writing 1 to `control`
+//! reboots to apply them. `status` reads and notifies the Wi-Fi state, or the outcome of the
+//! last write.
+//!
+//! `wifi` takes `{"ssid":"home","password":"secret","priority":10}`, `mqtt` takes
+//! `{"host":"broker.lan","port":1883,"username":"switch","password":"secret","prefix":"embedded"}`,
+//! absent fields keeping their current value.
+
+use defmt::*;
+use embassy_time::Timer;
+use heapless::{String, Vec};
+use serde::Deserialize;
+use trouble_host::prelude::*;
+
+use super::Server;
+use crate::config::{is_topic_level, KnownNetworks, MqttSettings, Secret, WifiNetwork};
+use crate::log_warn;
+use crate::net::wifi::WifiState;
+use crate::storage::SharedStorage;
+
+pub const MAX_WRITE: usize = 160;
+
+#[gatt_service(uuid = "6e7f1000-7d43-4c5c-9a4e-2b1f0e9f3a01")]
+pub struct ProvisioningService {
+    #[characteristic(uuid = "6e7f1001-7d43-4c5c-9a4e-2b1f0e9f3a01", write)]
+    pub wifi: Vec<u8, MAX_WRITE>,
+    #[characteristic(uuid = "6e7f1002-7d43-4c5c-9a4e-2b1f0e9f3a01", write)]
+    pub mqtt: Vec<u8, MAX_WRITE>,
+    #[characteristic(uuid = "6e7f1003-7d43-4c5c-9a4e-2b1f0e9f3a01", read, notify)]
+    pub status: String<32>,
+    #[characteristic(uuid = "6e7f1004-7d43-4c5c-9a4e-2b1f0e9f3a01", write)]
+    pub control: u8,
+}
+
+#[derive(Deserialize)]
+struct WifiWrite<'a> {
+    #[serde(borrow)]
+    ssid: &'a str,
+    #[serde(borrow, default)]
+    password: &'a str,
+    #[serde(default)]
+    priority: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct MqttWrite<'a> {
+    #[serde(borrow, default)]
+    host: Option<&'a str>,
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(borrow, default)]
+    username: Option<&'a str>,
+    #[serde(borrow, default)]
+    password: Option<&'a str>,
+    #[serde(borrow, default)]
+    prefix: Option<&'a str>,
+}
+
+fn status_text(text: &str) -> String<32> {
+    let mut status = String::new();
+    let _ = status.push_str(text);
+    status
+}
+
+fn state_name(state: WifiState) -> &'static str {
+    match state {
+        WifiState::Joining => "joining",
+        WifiState::Connected => "connected",
+        WifiState::Disconnected => "disconnected",
+    }
+}
+
+async fn set_status(server: &Server<'_>, connection: &GattConnection<'_, '_>, text: &str) {
+    let status = status_text(text);
+    if let Err(e) = server.provisioning.status.notify(connection, &status).await {
+        warn!("ble notify failed : {:?}", e);
+    }
+}
+
+/// Initial status, before any connection.
+pub async fn init(server: &Server<'_>, storage: &SharedStorage) {
+    let text = if KnownNetworks::load(storage).await.is_empty() {
+        "unprovisioned"
+    } else {
+        "provisioned"
+    };
+    if let Err(e) = server.set(&server.provisioning.status, &status_text(text)) {
+        warn!("ble status not set : {:?}", e);
+    }
+}
+
+pub async fn notify_state(server: &Server<'_>, connection: &GattConnection<'_, '_>, state: WifiState) {
+    set_status(server, connection, state_name(state)).await
+}
+
+/// Whether `handle` is one of the writable characteristics of the service.
+pub fn is_provisioning_handle(server: &Server<'_>, handle: u16) -> bool {
+    let service = &server.provisioning;
+    [service.wifi.handle, service.mqtt.handle, service.control.handle].contains(&handle)
+}
+
+async fn store_network(storage: &SharedStorage, data: &[u8]) -> Result<(), &'static str> {
+    let (write, _): (WifiWrite, _) =
+        serde_json_core::from_slice(data).map_err(|_| "invalid wifi json")?;
+    let network = WifiNetwork::new(write.ssid, write.password, write.priority.unwrap_or(u8::MAX))
+        .map_err(|_| "invalid network")?;
+    let mut networks = KnownNetworks::load(storage).await;
+    networks.remember(network);
+    networks.save(storage).await.map_err(|_| "storage failed")?;
+    info!("ble stored network {}", write.ssid);
+    Ok(())
+}
+
+async fn store_settings(storage: &SharedStorage, data: &[u8]) -> Result<(), &'static str> {
+    let (write, _): (MqttWrite, _) =
+        serde_json_core::from_slice(data).map_err(|_| "invalid mqtt json")?;
+    let mut settings = MqttSettings::load(storage).await;
+    if let Some(host) = write.host {
+        settings.broker.host.clear();
+        settings.broker.host.push_str(host).map_err(|_| "host too long")?;
+    }
+    if let Some(port) = write.port {
+        settings.broker.port = if port == 0 { None } else { Some(port) };
+    }
+    if let Some(username) = write.username {
+        settings.credentials.username.clear();
+        settings
+            .credentials
+            .username
+            .push_str(username)
+            .map_err(|_| "username too long")?;
+    }
+    if let Some(password) = write.password {
+        settings.credentials.password = Secret::new(password).ok_or("password too long")?;
+    }
+    if let Some(prefix) = write.prefix {
+        if !is_topic_level(prefix) {
+            return Err("invalid prefix");
+        }
+        settings.prefix.clear();
+        settings.prefix.push_str(prefix).map_err(|_| "prefix too long")?;
+    }
+    settings.save(storage).await.map_err(|_| "storage failed")?;
+    info!("ble stored mqtt settings");
+    Ok(())
+}
+
+/// Handles a write to one of the service characteristics.
+pub async fn on_write(
+    server: &Server<'_>,
+    connection: &GattConnection<'_, '_>,
+    handle: u16,
+    data: &[u8],
+    storage: &SharedStorage,
+) {
+    let service = &server.provisioning;
+    let result = if handle == service.wifi.handle {
+        store_network(storage, data).await
+    } else if handle == service.mqtt.handle {
+        store_settings(storage, data).await
+    } else if handle == service.control.handle {
+        if data == [1] {
+            info!("ble requested reboot");
+            set_status(server, connection, "rebooting").await;
+            // let the notification go out
+            Timer::after_millis(500).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        Err("unknown control")
+    } else {
+        return;
+    };
+    match result {
+        Ok(()) => set_status(server, connection, "saved").await,
+        Err(e) => {
+            log_warn!("ble provisioning : {}", e);
+            set_status(server, connection, e).await;
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Pico W bring-up shared by the binaries : the cyw43 radio on PIO0, its firmware and the
+//! embassy-net stack, or a W5500 module with the `ethernet-w5500` feature.
+//!
+//! The radio firmwares are baked into the program by the default `embedded-firmware` feature.
+//! To make flashing faster during development, building without it reads them from fixed
+//! addresses where they were flashed once instead. They are packaged with a header, see
+//! [`crate::firmware`], and checked at boot :
+//!
+//! ```text
+//! tools/pack_firmware.py ../embassy/cyw43-firmware --version 1
+//! probe-rs download 43439A0.pack --binary-format bin --chip RP2040 --base-address 0x10100000
+//! probe-rs download 43439A0_clm.pack --binary-format bin --chip RP2040 --base-address 0x10140000
+//! probe-rs download 43439A0_btfw.pack --binary-format bin --chip RP2040 --base-address 0x10141400
+//! ```
+
+#[cfg(not(feature = "embedded-firmware"))]
+use core::cell::Cell;
+
+use cyw43::bluetooth::BtDriver;
+use cyw43::{Control, NetDriver};
+use cyw43_pio::PioSpi;
+use defmt::*;
+use embassy_executor::Spawner;
+use embassy_net::{Stack, StackResources};
+use embassy_rp::bind_interrupts;
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::gpio::{Level, Output};
+use embassy_rp::peripherals::{DMA_CH0, PIN_23, PIN_24, PIN_25, PIN_29, PIO0};
+use embassy_rp::pio::{InterruptHandler, Pio};
+#[cfg(not(feature = "embedded-firmware"))]
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use rand::RngCore;
+use static_cell::StaticCell;
+
+use crate::firmware::{Blob, FirmwareError};
+use crate::net::power;
+#[cfg(not(feature = "ethernet-w5500"))]
+use crate::net::wifi;
+
+#[cfg(feature = "ethernet-w5500")]
+use crate::net::ethernet::{self, EthernetDevice, EthernetPins, EthernetRunner};
+
+bind_interrupts!(pub struct Irqs {
+    PIO0_IRQ_0 => InterruptHandler<PIO0>;
+});
+
+/// Sockets of the stack : dhcp, dns, mqtt, sntp and mdns, or the provisioning servers.
+const SOCKETS: usize = 6;
+
+/// Firmwares [`init_radio`] needs.
+pub const WIFI_FIRMWARE: &[Blob] = &[Blob::Wifi, Blob::Clm];
+/// Firmwares [`init_radio_with_bluetooth`] needs.
+pub const BLUETOOTH_FIRMWARE: &[Blob] = &[Blob::Wifi, Blob::Clm, Blob::Bluetooth];
+
+#[cfg(feature = "embedded-firmware")]
+pub fn firmware(blob: Blob) -> Result<&'static [u8], FirmwareError> {
+    Ok(match blob {
+        Blob::Wifi => &include_bytes!("../../embassy/cyw43-firmware/43439A0.bin")[..],
+        Blob::Clm => &include_bytes!("../../embassy/cyw43-firmware/43439A0_clm.bin")[..],
+        Blob::Bluetooth => &include_bytes!("../../embassy/cyw43-firmware/43439A0_btfw.bin")[..],
+    })
+}
+
+/// Flashed region of `blob`, header included.
+#[cfg(not(feature = "embedded-firmware"))]
+fn region(blob: Blob) -> &'static [u8] {
+    let (address, size) = match blob {
+        Blob::Wifi => (0x10100000, 0x40000),
+        Blob::Clm => (0x10140000, 0x1400),
+        Blob::Bluetooth => (0x10141400, 0x2000),
+    };
+    // SAFETY: the regions are in flash, which is mapped for the whole program and never written
+    // there, see memory.x
+    unsafe { core::slice::from_raw_parts(address as *const u8, size) }
+}
+
+/// A radio firmware, once its header and checksum are verified.
+#[cfg(not(feature = "embedded-firmware"))]
+pub fn firmware(blob: Blob) -> Result<&'static [u8], FirmwareError> {
+    static CHECKED: Mutex<ThreadModeRawMutex, Cell<[Option<&'static [u8]>; 3]>> =
+        Mutex::new(Cell::new([None; 3]));
+    let index = blob as usize;
+    if let Some(data) = CHECKED.lock(Cell::get)[index] {
+        return Ok(data);
+    }
+    let checked = crate::firmware::check(blob, region(blob))?;
+    info!("{} firmware version {}", blob, checked.version);
+    CHECKED.lock(|cell| {
+        let mut checked_blobs = cell.get();
+        checked_blobs[index] = Some(checked.data);
+        cell.set(checked_blobs);
+    });
+    Ok(checked.data)
+}
+
+const BLINK_LONG: Duration = Duration::from_millis(600);
+const BLINK_SHORT: Duration = Duration::from_millis(150);
+const BLINK_GAP: Duration = Duration::from_millis(600);
+const BLINK_PAUSE: Duration = Duration::from_secs(2);
+
+/// Checks the radio firmwares in `blobs`, [`WIFI_FIRMWARE`] or [`BLUETOOTH_FIRMWARE`], call it
+/// before starting the radio as the onboard led needs them.
+///
+/// When one is missing or damaged, `led` blinks forever : as many long flashes as the
+/// [`Blob::number`] of the firmware, then as many short ones as the [`FirmwareError::code`].
+/// `on` is the level lighting the led.
+pub async fn check_firmware(led: &mut Output<'_>, on: Level, blobs: &[Blob]) {
+    let failure = blobs
+        .iter()
+        .find_map(|&blob| firmware(blob).err().map(|error| (blob, error)));
+    let (blob, error) = match failure {
+        Some(failure) => failure,
+        None => return,
+    };
+    error!("{} firmware : {}", blob, error);
+    let off = Level::from(!bool::from(on));
+    loop {
+        for (count, duration) in [(blob.number(), BLINK_LONG), (error.code(), BLINK_SHORT)] {
+            for _ in 0..count {
+                led.set_level(on);
+                Timer::after(duration).await;
+                led.set_level(off);
+                Timer::after(BLINK_SHORT).await;
+            }
+            Timer::after(BLINK_GAP).await;
+        }
+        Timer::after(BLINK_PAUSE).await;
+    }
+}
+
+/// Peripherals wired to the radio on the Pico W.
+pub struct RadioPeripherals {
+    pub pwr: PIN_23,
+    pub cs: PIN_25,
+    pub dio: PIN_24,
+    pub clk: PIN_29,
+    pub pio: PIO0,
+    pub dma: DMA_CH0,
+}
+
+#[embassy_executor::task]
+async fn cyw43_task(
+    runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
+) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, NetDriver<'static>>) -> ! {
+    runner.run().await
+}
+
+fn radio_spi(
+    peripherals: RadioPeripherals,
+) -> (Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>) {
+    let pwr = Output::new(peripherals.pwr, Level::Low);
+    let cs = Output::new(peripherals.cs, Level::High);
+    let mut pio = Pio::new(peripherals.pio, Irqs);
+    let spi = PioSpi::new(
+        &mut pio.common,
+        pio.sm0,
+        pio.irq0,
+        cs,
+        peripherals.dio,
+        peripherals.clk,
+        peripherals.dma,
+    );
+    (pwr, spi)
+}
+
+async fn init_control(control: &mut Control<'static>, clm: &[u8]) {
+    control.init(clm).await;
+    control.set_power_management(power::profile().mode()).await;
+}
+
+/// Starts the radio, leaving the stack to [`init_stack`].
+pub async fn init_radio(
+    spawner: Spawner,
+    peripherals: RadioPeripherals,
+) -> (NetDriver<'static>, Control<'static>) {
+    let (fw, clm) = (unwrap!(firmware(Blob::Wifi)), unwrap!(firmware(Blob::Clm)));
+    let (pwr, spi) = radio_spi(peripherals);
+    static STATE: StaticCell<cyw43::State> = StaticCell::new();
+    let state = STATE.init(cyw43::State::new());
+    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+    unwrap!(spawner.spawn(cyw43_task(runner)));
+    init_control(&mut control, clm).await;
+    (net_device, control)
+}
+
+/// Starts the radio with its bluetooth controller.
+pub async fn init_radio_with_bluetooth(
+    spawner: Spawner,
+    peripherals: RadioPeripherals,
+) -> (NetDriver<'static>, BtDriver<'static>, Control<'static>) {
+    let (fw, clm) = (unwrap!(firmware(Blob::Wifi)), unwrap!(firmware(Blob::Clm)));
+    let btfw = unwrap!(firmware(Blob::Bluetooth));
+    let (pwr, spi) = radio_spi(peripherals);
+    static STATE: StaticCell<cyw43::State> = StaticCell::new();
+    let state = STATE.init(cyw43::State::new());
+    let (net_device, bt_device, mut control, runner) =
+        cyw43::new_with_bluetooth(state, pwr, spi, fw, btfw).await;
+    unwrap!(spawner.spawn(cyw43_task(runner)));
+    init_control(&mut control, clm).await;
+    (net_device, bt_device, control)
+}
+
+fn seed() -> u64 {
+    RoscRng.next_u64()
+}
+
+/// Runs the network stack on the radio.
+pub fn init_stack(
+    spawner: Spawner,
+    net_device: NetDriver<'static>,
+    config: embassy_net::Config,
+) -> Stack<'static> {
+    static RESOURCES: StaticCell<StackResources<SOCKETS>> = StaticCell::new();
+    let resources = RESOURCES.init(StackResources::new());
+    let (stack, runner) = embassy_net::new(net_device, config, resources, seed());
+    unwrap!(spawner.spawn(net_task(runner)));
+    stack
+}
+
+/// Starts the radio and the network stack, the network being joined through the returned
+/// control.
+pub async fn init_network(
+    spawner: Spawner,
+    peripherals: RadioPeripherals,
+    config: embassy_net::Config,
+) -> (Stack<'static>, Control<'static>) {
+    let (net_device, control) = init_radio(spawner, peripherals).await;
+    (init_stack(spawner, net_device, config), control)
+}
+
+#[cfg(feature = "ethernet-w5500")]
+#[embassy_executor::task]
+async fn ethernet_task(runner: EthernetRunner) -> ! {
+    runner.run().await
+}
+
+#[cfg(feature = "ethernet-w5500")]
+#[embassy_executor::task]
+async fn ethernet_net_task(mut runner: embassy_net::Runner<'static, EthernetDevice>) -> ! {
+    runner.run().await
+}
+
+/// Starts the W5500 module and the network stack, see [`ethernet`].
+#[cfg(feature = "ethernet-w5500")]
+pub async fn init_ethernet(
+    spawner: Spawner,
+    pins: EthernetPins,
+    mac_address: [u8; 6],
+    config: embassy_net::Config,
+) -> Stack<'static> {
+    let (net_device, runner) = ethernet::new(pins, mac_address).await;
+    unwrap!(spawner.spawn(ethernet_task(runner)));
+    static RESOURCES: StaticCell<StackResources<SOCKETS>> = StaticCell::new();
+    let resources = RESOURCES.init(StackResources::new());
+    let (stack, runner) = embassy_net::new(net_device, config, resources, seed());
+    unwrap!(spawner.spawn(ethernet_net_task(runner)));
+    stack
+}
+
+/// Waits until the Wi-Fi network is joined.
+#[cfg(not(feature = "ethernet-w5500"))]
+pub async fn wait_link_up(_stack: Stack<'_>) {
+    wifi::wait_connected().await
+}
+
+#[cfg(feature = "ethernet-w5500")]
+pub async fn wait_link_up(stack: Stack<'_>) {
+    stack.wait_link_up().await
+}
+
+/// Waits until the Wi-Fi network is left, while [`wifi::run`] looks for one to join again.
+#[cfg(not(feature = "ethernet-w5500"))]
+pub async fn wait_link_down(_stack: Stack<'_>) {
+    wifi::wait_disconnected().await
+}
+
+#[cfg(feature = "ethernet-w5500")]
+pub async fn wait_link_down(stack: Stack<'_>) {
+    stack.wait_link_down().await
+}
+
+/// Waits until the stack has an address, from DHCP or otherwise.
+pub async fn wait_for_address(stack: Stack<'_>) {
+    if !stack.is_config_up() {
+        info!("waiting for an address...");
+        stack.wait_config_up().await;
+        info!("address is now up!");
+    }
+}
@@ -0,0 +1,177 @@
+//! Wall clock, set from the retained `{prefix}/time` and `{prefix}/local_time` topics or SNTP.
+//!
+//! Both topics carry unix timestamps in seconds, `local_time` being shifted by the local
+//! UTC offset, so the difference between the two gives the offset.
+
+use core::cell::Cell;
+
+use defmt::*;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::Instant;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum TimeSource {
+    Mqtt,
+    Sntp,
+}
+
+#[derive(Clone, Copy)]
+struct ClockState {
+    /// Unix time in milliseconds at `Instant` zero, once synchronized.
+    boot_unix_ms: Option<u64>,
+    /// Last `local_time` received, in seconds, with the uptime in milliseconds it arrived at.
+    local_reference: Option<(i64, u64)>,
+    local_offset_s: i32,
+    source: Option<TimeSource>,
+}
+
+static CLOCK: Mutex<ThreadModeRawMutex, Cell<ClockState>> = Mutex::new(Cell::new(ClockState {
+    boot_unix_ms: None,
+    local_reference: None,
+    local_offset_s: 0,
+    source: None,
+}));
+
+fn update(f: impl FnOnce(&mut ClockState)) {
+    CLOCK.lock(|clock| {
+        let mut state = clock.get();
+        f(&mut state);
+        clock.set(state);
+    })
+}
+
+/// Point in time, with the local offset in effect at that time.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct Timestamp {
+    pub unix_ms: u64,
+    pub local_offset_s: i32,
+}
+
+/// Broken down date, in local time unless noted.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct DateTime {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+    /// 0 is Monday.
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Timestamp {
+    pub fn unix_seconds(&self) -> u64 {
+        self.unix_ms / 1000
+    }
+    pub fn local_seconds(&self) -> i64 {
+        self.unix_seconds() as i64 + self.local_offset_s as i64
+    }
+    pub fn utc(&self) -> DateTime {
+        DateTime::from_unix(self.unix_seconds() as i64)
+    }
+    pub fn local(&self) -> DateTime {
+        DateTime::from_unix(self.local_seconds())
+    }
+}
+
+impl DateTime {
+    /// Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+    pub fn from_unix(seconds: i64) -> Self {
+        let days = seconds.div_euclid(86_400);
+        let time = seconds.rem_euclid(86_400);
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+        let year = (yoe + era * 400) as i32 + if month <= 2 { 1 } else { 0 };
+        DateTime {
+            year,
+            month,
+            day,
+            // 1970-01-01 was a Thursday
+            weekday: (days + 3).rem_euclid(7) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+pub fn is_synchronized() -> bool {
+    CLOCK.lock(|clock| clock.get().boot_unix_ms.is_some())
+}
+
+pub fn source() -> Option<TimeSource> {
+    CLOCK.lock(|clock| clock.get().source)
+}
+
+/// Current time, if the clock was ever synchronized.
+pub fn now() -> Option<Timestamp> {
+    let state = CLOCK.lock(|clock| clock.get());
+    Some(Timestamp {
+        unix_ms: state.boot_unix_ms? + Instant::now().as_millis(),
+        local_offset_s: state.local_offset_s,
+    })
+}
+
+pub fn set_unix_ms(unix_ms: u64, source: TimeSource) {
+    let boot = unix_ms.saturating_sub(Instant::now().as_millis());
+    update(|state| {
+        if let Some(previous) = state.boot_unix_ms {
+            debug!("clock adjusted by {} ms", boot as i64 - previous as i64);
+        } else {
+            info!("clock synchronized from {} : {}", source, unix_ms / 1000);
+        }
+        state.boot_unix_ms = Some(boot);
+        state.source = Some(source);
+        refresh_offset(state);
+    });
+}
+
+/// Derives the local offset once both the time and a local time are known.
+fn refresh_offset(state: &mut ClockState) {
+    if let (Some(boot), Some((local, received_at))) = (state.boot_unix_ms, state.local_reference) {
+        let utc = ((boot + received_at) / 1000) as i64;
+        // offsets are whole quarter hours, which absorbs the delay between both messages
+        let offset = ((local - utc + 450).div_euclid(900) * 900) as i32;
+        if offset != state.local_offset_s {
+            info!("local time offset : {} s", offset);
+        }
+        state.local_offset_s = offset;
+    }
+}
+
+/// Parses a unix timestamp in seconds, optionally with a fractional part.
+fn parse_unix_ms(payload: &[u8]) -> Option<u64> {
+    let text = core::str::from_utf8(payload).ok()?.trim();
+    let (seconds, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let mut ms: u64 = seconds.parse::<u64>().ok()? * 1000;
+    for (digit, scale) in fraction.bytes().zip([100, 10, 1]) {
+        ms += (digit as char).to_digit(10)? as u64 * scale;
+    }
+    Some(ms)
+}
+
+/// Handles a `{prefix}/time` payload.
+pub fn on_time_message(payload: &[u8]) {
+    match parse_unix_ms(payload) {
+        Some(unix_ms) => set_unix_ms(unix_ms, TimeSource::Mqtt),
+        None => warn!("invalid time payload"),
+    }
+}
+
+/// Handles a `{prefix}/local_time` payload, which only sets the offset.
+pub fn on_local_time_message(payload: &[u8]) {
+    match parse_unix_ms(payload) {
+        Some(local_ms) => update(|state| {
+            state.local_reference = Some(((local_ms / 1000) as i64, Instant::now().as_millis()));
+            refresh_offset(state);
+        }),
+        None => warn!("invalid local time payload"),
+    }
+}
@@ -0,0 +1,553 @@
+//! Device configuration, defaulting to values given at build time.
+
+use embassy_net::{ConfigV6, Ipv4Address, Ipv4Cidr, Ipv6Cidr, StaticConfigV4, StaticConfigV6};
+use embassy_time::Duration;
+use heapless::String;
+
+use crate::storage::{self, SharedStorage, Slot, StorageError};
+
+/// Sensitive value, redacted in `defmt` and `Debug` output.
+#[derive(Clone, Default)]
+pub struct Secret<const N: usize>(String<N>);
+
+impl<const N: usize> Secret<N> {
+    pub fn new(value: &str) -> Option<Self> {
+        let mut secret = String::new();
+        secret.push_str(value).ok()?;
+        Some(Self(secret))
+    }
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<const N: usize> defmt::Format for Secret<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "<redacted>")
+    }
+}
+
+impl<const N: usize> core::fmt::Debug for Secret<N> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+pub const MAX_NETWORKS: usize = 4;
+
+/// Network to join, with an empty password for open networks.
+#[derive(Clone, Default, defmt::Format)]
+pub struct WifiNetwork {
+    pub ssid: String<32>,
+    pub password: Secret<64>,
+    /// Preferred over visible networks of a lower priority, whatever their signal.
+    pub priority: u8,
+}
+
+impl WifiNetwork {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Result<Self, NetworksError> {
+        let mut network = Self {
+            priority,
+            ..Self::default()
+        };
+        if ssid.is_empty() {
+            return Err(NetworksError::InvalidSsid);
+        }
+        network
+            .ssid
+            .push_str(ssid)
+            .map_err(|_| NetworksError::InvalidSsid)?;
+        network.password = Secret::new(password).ok_or(NetworksError::PasswordTooLong)?;
+        Ok(network)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NetworksError {
+    TooMany,
+    /// Empty or longer than 32 bytes.
+    InvalidSsid,
+    PasswordTooLong,
+    InvalidPriority,
+    Corrupt,
+}
+
+/// Networks the device may join, see `net::wifi` for how one is picked.
+#[derive(Clone, Default, defmt::Format)]
+pub struct KnownNetworks(heapless::Vec<WifiNetwork, MAX_NETWORKS>);
+
+impl KnownNetworks {
+    /// From `WIFI_SSID` and `WIFI_PASSWORD` at build time, and `WIFI_NETWORKS`, a `;` separated
+    /// list of `priority:ssid:password` entries.
+    pub fn from_env() -> Self {
+        let mut networks = Self::default();
+        if let Some(ssid) = option_env!("WIFI_SSID") {
+            let password = option_env!("WIFI_PASSWORD").unwrap_or_default();
+            networks
+                .add(WifiNetwork::new(ssid, password, 0).expect("invalid WIFI_SSID or WIFI_PASSWORD"))
+                .expect("too many networks");
+        }
+        for entry in option_env!("WIFI_NETWORKS")
+            .unwrap_or_default()
+            .split(';')
+            .filter(|entry| !entry.is_empty())
+        {
+            let network = Self::parse_entry(entry).expect("invalid WIFI_NETWORKS entry");
+            networks.add(network).expect("too many networks");
+        }
+        networks
+    }
+
+    fn parse_entry(entry: &str) -> Result<WifiNetwork, NetworksError> {
+        let mut parts = entry.splitn(3, ':');
+        let priority = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| NetworksError::InvalidPriority)?;
+        let ssid = parts.next().ok_or(NetworksError::InvalidSsid)?;
+        WifiNetwork::new(ssid, parts.next().unwrap_or_default(), priority)
+    }
+
+    /// Adds `network`, replacing a known network with the same SSID.
+    pub fn add(&mut self, network: WifiNetwork) -> Result<(), NetworksError> {
+        match self.0.iter_mut().find(|known| known.ssid == network.ssid) {
+            Some(known) => *known = network,
+            None => self.0.push(network).map_err(|_| NetworksError::TooMany)?,
+        }
+        Ok(())
+    }
+
+    /// Adds `network`, forgetting the least preferred network to make room when full.
+    pub fn remember(&mut self, network: WifiNetwork) {
+        if self.get(&network.ssid).is_none() && self.0.is_full() {
+            if let Some(weakest) = self.0.iter().position(|known| {
+                self.0.iter().all(|other| other.priority >= known.priority)
+            }) {
+                self.0.remove(weakest);
+            }
+        }
+        // there is room now
+        let _ = self.add(network);
+    }
+
+    pub fn remove(&mut self, ssid: &str) {
+        self.0.retain(|known| known.ssid != ssid);
+    }
+
+    pub fn get(&self, ssid: &str) -> Option<&WifiNetwork> {
+        self.0.iter().find(|known| known.ssid == ssid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WifiNetwork> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Stored as a priority, then length prefixed SSID and password, for each network.
+    fn encode(&self, out: &mut heapless::Vec<u8, { MAX_NETWORKS * 99 }>) -> Result<(), StorageError> {
+        for network in self.iter() {
+            storage::push_field(out, network.ssid.as_bytes())?;
+            storage::push_field(out, network.password.expose().as_bytes())?;
+            out.push(network.priority).map_err(|_| StorageError::TooLarge)?;
+        }
+        Ok(())
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self, NetworksError> {
+        let mut networks = Self::default();
+        while !data.is_empty() {
+            let ssid = storage::take_field(&mut data).ok_or(NetworksError::Corrupt)?;
+            let password = storage::take_field(&mut data).ok_or(NetworksError::Corrupt)?;
+            let (priority, rest) = data.split_first().ok_or(NetworksError::Corrupt)?;
+            networks.add(WifiNetwork::new(ssid, password, *priority)?)?;
+            data = rest;
+        }
+        Ok(networks)
+    }
+
+    /// The networks saved by [`KnownNetworks::save`], or those given at build time.
+    pub async fn load(storage: &SharedStorage) -> Self {
+        let mut buffer = [0; MAX_NETWORKS * 99];
+        match storage.lock().await.read(Slot::Networks, &mut buffer).map(Self::decode) {
+            Some(Ok(networks)) => networks,
+            Some(Err(e)) => {
+                defmt::warn!("invalid saved networks : {}", e);
+                Self::from_env()
+            }
+            None => Self::from_env(),
+        }
+    }
+
+    pub async fn save(&self, storage: &SharedStorage) -> Result<(), StorageError> {
+        let mut data = heapless::Vec::new();
+        self.encode(&mut data)?;
+        storage.lock().await.write(Slot::Networks, &data)
+    }
+}
+
+/// Broker login, both empty for anonymous access.
+#[derive(Clone, Default)]
+pub struct MqttCredentials {
+    pub username: String<32>,
+    pub password: Secret<64>,
+}
+
+impl defmt::Format for MqttCredentials {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "MqttCredentials {{ username: <redacted>, password: <redacted> }}")
+    }
+}
+
+impl MqttCredentials {
+    /// From `MQTT_USERNAME` and `MQTT_PASSWORD` at build time.
+    pub fn from_env() -> Self {
+        let mut credentials = Self::default();
+        if let Some(username) = option_env!("MQTT_USERNAME") {
+            credentials
+                .username
+                .push_str(username)
+                .expect("MQTT_USERNAME too long");
+        }
+        if let Some(password) = option_env!("MQTT_PASSWORD") {
+            credentials.password = Secret::new(password).expect("MQTT_PASSWORD too long");
+        }
+        credentials
+    }
+}
+
+/// Where to find the broker, see `net::broker::resolve`.
+#[derive(Clone, defmt::Format)]
+pub struct BrokerConfig {
+    /// DNS or `.local` host name, empty to browse for `_mqtt._tcp.local`.
+    pub host: String<64>,
+    /// Port to use when not advertised over mDNS, defaults to 1883, or 8883 with TLS.
+    pub port: Option<u16>,
+    /// Used when the host can not be resolved.
+    pub fallback: Ipv4Address,
+}
+
+impl BrokerConfig {
+    /// From `MQTT_BROKER_HOST`, `MQTT_BROKER_PORT` and `MQTT_BROKER_ADDRESS` at build time.
+    pub fn from_env() -> Self {
+        let mut host = String::new();
+        if let Some(name) = option_env!("MQTT_BROKER_HOST") {
+            host.push_str(name).expect("MQTT_BROKER_HOST too long");
+        }
+        let port = option_env!("MQTT_BROKER_PORT")
+            .map(|port| port.parse().expect("MQTT_BROKER_PORT is not a port number"));
+        let fallback = match option_env!("MQTT_BROKER_ADDRESS") {
+            Some(address) => {
+                let address: core::net::Ipv4Addr =
+                    address.parse().expect("MQTT_BROKER_ADDRESS is not an ipv4 address");
+                let [a, b, c, d] = address.octets();
+                Ipv4Address::new(a, b, c, d)
+            }
+            None => Ipv4Address::new(192, 168, 103, 2),
+        };
+        Self {
+            host,
+            port,
+            fallback,
+        }
+    }
+}
+
+pub const DEFAULT_PREFIX: &str = "embedded";
+
+/// Broker, login and topic prefix, as given at build time or set during provisioning.
+#[derive(Clone, defmt::Format)]
+pub struct MqttSettings {
+    pub broker: BrokerConfig,
+    pub credentials: MqttCredentials,
+    pub prefix: String<24>,
+}
+
+impl MqttSettings {
+    /// From the broker and credentials variables, and `MQTT_PREFIX`, at build time.
+    pub fn from_env() -> Self {
+        let mut prefix = String::new();
+        prefix
+            .push_str(option_env!("MQTT_PREFIX").unwrap_or(DEFAULT_PREFIX))
+            .expect("MQTT_PREFIX too long");
+        Self {
+            broker: BrokerConfig::from_env(),
+            credentials: MqttCredentials::from_env(),
+            prefix,
+        }
+    }
+
+    /// Stored as length prefixed host, port, username, password and prefix, the fallback
+    /// address always coming from the build.
+    fn encode(&self, out: &mut heapless::Vec<u8, 256>) -> Result<(), StorageError> {
+        let mut port: String<5> = String::new();
+        if let Some(value) = self.broker.port {
+            let _ = core::fmt::write(&mut port, format_args!("{}", value));
+        }
+        storage::push_field(out, self.broker.host.as_bytes())?;
+        storage::push_field(out, port.as_bytes())?;
+        storage::push_field(out, self.credentials.username.as_bytes())?;
+        storage::push_field(out, self.credentials.password.expose().as_bytes())?;
+        storage::push_field(out, self.prefix.as_bytes())
+    }
+
+    fn decode(mut data: &[u8]) -> Option<Self> {
+        let mut settings = Self::from_env();
+        settings.broker.host.clear();
+        settings.broker.host.push_str(storage::take_field(&mut data)?).ok()?;
+        settings.broker.port = match storage::take_field(&mut data)? {
+            "" => None,
+            port => Some(port.parse().ok()?),
+        };
+        settings.credentials.username.clear();
+        settings.credentials.username.push_str(storage::take_field(&mut data)?).ok()?;
+        settings.credentials.password = Secret::new(storage::take_field(&mut data)?)?;
+        settings.prefix.clear();
+        settings.prefix.push_str(storage::take_field(&mut data)?).ok()?;
+        Some(settings)
+    }
+
+    /// The settings saved by [`MqttSettings::save`], or those given at build time.
+    pub async fn load(storage: &SharedStorage) -> Self {
+        let mut buffer = [0; 256];
+        match storage.lock().await.read(Slot::Mqtt, &mut buffer).map(Self::decode) {
+            Some(Some(settings)) => settings,
+            Some(None) => {
+                defmt::warn!("invalid saved mqtt settings");
+                Self::from_env()
+            }
+            None => Self::from_env(),
+        }
+    }
+
+    pub async fn save(&self, storage: &SharedStorage) -> Result<(), StorageError> {
+        let mut data = heapless::Vec::new();
+        self.encode(&mut data)?;
+        storage.lock().await.write(Slot::Mqtt, &data)
+    }
+}
+
+/// Device position, for sunrise and sunset schedules.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Location {
+    /// Degrees, north positive.
+    pub latitude: f32,
+    /// Degrees, east positive.
+    pub longitude: f32,
+}
+
+impl Location {
+    /// From `LATITUDE` and `LONGITUDE` at build time, `None` if either is missing.
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            latitude: option_env!("LATITUDE")?
+                .parse()
+                .expect("LATITUDE is not a number"),
+            longitude: option_env!("LONGITUDE")?
+                .parse()
+                .expect("LONGITUDE is not a number"),
+        })
+    }
+}
+
+pub const MAX_DNS_SERVERS: usize = 3;
+
+/// How the IPv4 address is obtained.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ipv4Mode {
+    Dhcp,
+    Static(StaticConfigV4),
+}
+
+/// How the IPv6 address is obtained.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ipv6Mode {
+    Off,
+    /// Link-local address derived from the hardware address, as in SLAAC.
+    LinkLocal,
+    Static(StaticConfigV6),
+}
+
+/// Addressing of the station interface, see `net::ip`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IpSettings {
+    pub ipv4: Ipv4Mode,
+    /// How long to wait for a DHCP lease before using `fallback` or reporting the failure.
+    pub dhcp_timeout: Option<Duration>,
+    pub fallback: Option<StaticConfigV4>,
+    pub ipv6: Ipv6Mode,
+}
+
+/// Parses `address/prefix length`, the length defaulting to `default_len`.
+fn parse_cidr<A: core::str::FromStr>(text: &str, default_len: u8) -> Option<(A, u8)> {
+    let (address, len) = match text.split_once('/') {
+        Some((address, len)) => (address, len.parse().ok()?),
+        None => (text, default_len),
+    };
+    Some((address.trim().parse().ok()?, len))
+}
+
+fn static_v4(
+    address: &str,
+    gateway: Option<Ipv4Address>,
+    dns_servers: &heapless::Vec<Ipv4Address, MAX_DNS_SERVERS>,
+) -> StaticConfigV4 {
+    let (address, len) = parse_cidr(address, 24).expect("invalid ipv4 address, expected a.b.c.d/len");
+    StaticConfigV4 {
+        address: Ipv4Cidr::new(address, len),
+        gateway,
+        dns_servers: dns_servers.clone(),
+    }
+}
+
+impl IpSettings {
+    /// From build time variables :
+    /// - `IPV4_ADDRESS`, as `a.b.c.d/len`, for a static address rather than DHCP,
+    ///   with `IPV4_GATEWAY` and `DNS_SERVERS`, a comma separated list of up to 3 addresses,
+    /// - `DHCP_TIMEOUT`, in seconds, and `DHCP_FALLBACK`, the static address used after it,
+    ///   with the same gateway and DNS servers,
+    /// - `IPV6_ADDRESS`, `auto` for a link-local address, or `addr/len` with `IPV6_GATEWAY`.
+    pub fn from_env() -> Self {
+        let gateway = option_env!("IPV4_GATEWAY")
+            .map(|gateway| gateway.parse().expect("IPV4_GATEWAY is not an ipv4 address"));
+        let mut dns_servers = heapless::Vec::new();
+        for server in option_env!("DNS_SERVERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|server| !server.trim().is_empty())
+        {
+            let server = server.trim().parse().expect("DNS_SERVERS holds an invalid ipv4 address");
+            dns_servers.push(server).expect("more than 3 DNS_SERVERS");
+        }
+        let ipv4 = match option_env!("IPV4_ADDRESS") {
+            Some(address) => Ipv4Mode::Static(static_v4(address, gateway, &dns_servers)),
+            None => Ipv4Mode::Dhcp,
+        };
+        let dhcp_timeout = option_env!("DHCP_TIMEOUT").map(|seconds| {
+            Duration::from_secs(seconds.parse().expect("DHCP_TIMEOUT is not a number of seconds"))
+        });
+        let fallback =
+            option_env!("DHCP_FALLBACK").map(|address| static_v4(address, gateway, &dns_servers));
+        let ipv6 = match option_env!("IPV6_ADDRESS") {
+            None => Ipv6Mode::Off,
+            Some("auto") => Ipv6Mode::LinkLocal,
+            Some(address) => {
+                let (address, len) =
+                    parse_cidr(address, 64).expect("invalid ipv6 address, expected addr/len or auto");
+                Ipv6Mode::Static(StaticConfigV6 {
+                    address: Ipv6Cidr::new(address, len),
+                    gateway: option_env!("IPV6_GATEWAY")
+                        .map(|gateway| gateway.parse().expect("IPV6_GATEWAY is not an ipv6 address")),
+                    dns_servers: heapless::Vec::new(),
+                })
+            }
+        };
+        Self {
+            ipv4,
+            dhcp_timeout,
+            fallback,
+            ipv6,
+        }
+    }
+
+    /// Initial configuration of the stack, the link-local IPv6 address is set once the hardware
+    /// address is known, see `net::ip::run`.
+    pub fn stack_config(&self) -> embassy_net::Config {
+        let mut config = match &self.ipv4 {
+            Ipv4Mode::Dhcp => embassy_net::Config::dhcpv4(Default::default()),
+            Ipv4Mode::Static(config) => embassy_net::Config::ipv4_static(config.clone()),
+        };
+        if let Ipv6Mode::Static(ipv6) = &self.ipv6 {
+            config.ipv6 = ConfigV6::Static(ipv6.clone());
+        }
+        config
+    }
+}
+
+/// Whether `name` can be used as a single topic level, with no separator nor wildcard.
+pub fn is_topic_level(name: &str) -> bool {
+    !name.is_empty() && !name.contains(|c: char| matches!(c, '/' | '+' | '#'))
+}
+
+pub const MAX_GROUPS: usize = 4;
+
+/// Groups the device is a member of, controlled through `{prefix}/group/<name>/...`.
+#[derive(Clone, Default, PartialEq, Eq, defmt::Format)]
+pub struct Groups(heapless::Vec<String<16>, MAX_GROUPS>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GroupsError {
+    TooMany,
+    NameTooLong,
+    /// Empty, or containing a topic separator or wildcard.
+    InvalidName,
+}
+
+impl Groups {
+    /// Parses a comma separated list, such as `hall,floor-1`.
+    pub fn parse(list: &str) -> Result<Self, GroupsError> {
+        let mut groups = Self::default();
+        for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if !is_topic_level(name) {
+                return Err(GroupsError::InvalidName);
+            }
+            if groups.contains(name) {
+                continue;
+            }
+            let mut group = String::new();
+            group.push_str(name).map_err(|_| GroupsError::NameTooLong)?;
+            groups.0.push(group).map_err(|_| GroupsError::TooMany)?;
+        }
+        Ok(groups)
+    }
+
+    /// From `MQTT_GROUPS` at build time.
+    pub fn from_env() -> Self {
+        Self::parse(option_env!("MQTT_GROUPS").unwrap_or_default()).expect("invalid MQTT_GROUPS")
+    }
+
+    /// The groups saved by [`Groups::save`], or those given at build time.
+    pub async fn load(storage: &SharedStorage) -> Self {
+        let mut buffer = [0; MAX_GROUPS * 17];
+        let saved = storage.lock().await.read(Slot::Groups, &mut buffer).map(core::str::from_utf8);
+        match saved {
+            Some(Ok(list)) => Self::parse(list).unwrap_or_else(|e| {
+                defmt::warn!("invalid saved groups : {}", e);
+                Self::from_env()
+            }),
+            _ => Self::from_env(),
+        }
+    }
+
+    pub async fn save(&self, storage: &SharedStorage) -> Result<(), StorageError> {
+        let mut list: String<{ MAX_GROUPS * 17 }> = String::new();
+        // each name is at most 16 bytes, plus a comma
+        let _ = self.write(&mut list);
+        storage.lock().await.write(Slot::Groups, list.as_bytes())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|group| group == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|group| group.as_str())
+    }
+
+    /// Writes the comma separated list, as parsed by [`Groups::parse`].
+    pub fn write(&self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        for (index, group) in self.iter().enumerate() {
+            if index > 0 {
+                out.write_char(',')?;
+            }
+            out.write_str(group)?;
+        }
+        Ok(())
+    }
+}
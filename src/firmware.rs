@@ -0,0 +1,128 @@
+//! Header of the radio firmwares flashed apart from the program, see `board`.
+//!
+//! Each region starts with a 32 bytes little endian header, followed by the blob :
+//!
+//! | offset | size | field                                      |
+//! |--------|------|--------------------------------------------|
+//! | 0      | 4    | magic, `CYWF`                              |
+//! | 4      | 2    | header format, [`FORMAT`]                  |
+//! | 6      | 2    | blob kind, see [`Blob`]                    |
+//! | 8      | 4    | blob version, as given when packaging      |
+//! | 12     | 4    | blob length                                |
+//! | 16     | 4    | CRC-32 (IEEE) of the blob                  |
+//! | 20     | 12   | reserved, zero                             |
+//!
+//! `tools/pack_firmware.py` writes regions in this format.
+
+use defmt::Format;
+
+pub const MAGIC: [u8; 4] = *b"CYWF";
+pub const FORMAT: u16 = 1;
+pub const HEADER_SIZE: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Blob {
+    /// Wi-Fi firmware, `43439A0.bin`.
+    Wifi = 0,
+    /// Country locale matrix, `43439A0_clm.bin`.
+    Clm = 1,
+    /// Bluetooth firmware, `43439A0_btfw.bin`.
+    Bluetooth = 2,
+}
+
+impl Blob {
+    /// 1 based, used in the error pattern of `board::check_firmware`.
+    pub fn number(&self) -> u8 {
+        *self as u8 + 1
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum FirmwareError {
+    /// No header, the region was not flashed or holds a raw blob.
+    Missing,
+    UnknownFormat(u16),
+    /// The region holds another blob.
+    WrongKind(u16),
+    /// The announced length overflows the region.
+    TooLong(u32),
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl FirmwareError {
+    /// Number of short blinks of the error pattern of `board::check_firmware`.
+    pub fn code(&self) -> u8 {
+        match self {
+            FirmwareError::Missing => 1,
+            FirmwareError::UnknownFormat(_) | FirmwareError::WrongKind(_) => 2,
+            FirmwareError::TooLong(_) => 3,
+            FirmwareError::Checksum { .. } => 4,
+        }
+    }
+}
+
+/// A validated blob.
+#[derive(Clone, Copy, Format)]
+pub struct Checked {
+    pub version: u32,
+    pub data: &'static [u8],
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 as in zlib and Ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// Checks that `region` holds a packaged `blob`, returning the blob.
+pub fn check(blob: Blob, region: &'static [u8]) -> Result<Checked, FirmwareError> {
+    if region.len() < HEADER_SIZE || region[..4] != MAGIC {
+        return Err(FirmwareError::Missing);
+    }
+    let format = u16_at(region, 4);
+    if format != FORMAT {
+        return Err(FirmwareError::UnknownFormat(format));
+    }
+    let kind = u16_at(region, 6);
+    if kind != blob as u16 {
+        return Err(FirmwareError::WrongKind(kind));
+    }
+    let version = u32_at(region, 8);
+    let len = u32_at(region, 12);
+    let data = region
+        .get(HEADER_SIZE..HEADER_SIZE + len as usize)
+        .ok_or(FirmwareError::TooLong(len))?;
+    let expected = u32_at(region, 16);
+    let actual = crc32(data);
+    if actual != expected {
+        return Err(FirmwareError::Checksum { expected, actual });
+    }
+    Ok(Checked { version, data })
+}
@@ -1 +1,71 @@
+use defmt::*;
+use embassy_rp::gpio::Input;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel};
+use embassy_time::{Duration, Timer};
 
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum ButtonEvent {
+    Press,
+    Release,
+}
+
+impl ButtonEvent {
+    pub const ALL: [ButtonEvent; 2] = [ButtonEvent::Press, ButtonEvent::Release];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ButtonEvent::Press => "press",
+            ButtonEvent::Release => "release",
+        }
+    }
+}
+
+impl core::fmt::Display for ButtonEvent {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Button events, tagged with the 1 based button id.
+pub type ButtonChannel = Channel<ThreadModeRawMutex, (usize, ButtonEvent), 8>;
+
+const DEBOUNCE: Duration = Duration::from_millis(20);
+
+/// Push button wired to ground, the input is expected to be pulled up.
+pub struct Button<'a> {
+    pub input: Input<'a>,
+}
+
+impl Button<'_> {
+    pub fn is_pressed(&self) -> bool {
+        self.input.is_low()
+    }
+    /// Sends the events of the button to `channel`, and offers them to `mirror`, which drops
+    /// them while it is full.
+    pub async fn task(
+        &mut self,
+        id: usize,
+        channel: &'static ButtonChannel,
+        mirror: Option<&'static ButtonChannel>,
+    ) -> ! {
+        let mut pressed = self.is_pressed();
+        loop {
+            self.input.wait_for_any_edge().await;
+            Timer::after(DEBOUNCE).await;
+            if self.is_pressed() == pressed {
+                continue;
+            }
+            pressed = self.is_pressed();
+            let event = if pressed {
+                ButtonEvent::Press
+            } else {
+                ButtonEvent::Release
+            };
+            debug!("button {} : {}", id, event);
+            if let Some(mirror) = mirror {
+                let _ = mirror.try_send((id, event));
+            }
+            channel.send((id, event)).await;
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, channel::Channel, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+use itertools::Itertools;
+
+use crate::log_warn;
+use crate::mqtt::json::{LedCommand, MAX_JSON_PAYLOAD};
+use crate::output::leds::{Anim, Color, LedStatus};
+use crate::telemetry;
+
+pub struct ChannelMessage {
+    pub topic: String<12>,
+    pub id: String<8>,
+    pub data: String<8>,
+    pub payload: String<MAX_JSON_PAYLOAD>,
+}
+
+pub type InputChannel = Channel<ThreadModeRawMutex, ChannelMessage, 6>;
+
+/// Led state changes, tagged with the 1 based led id.
+pub type LedStateChannel = Channel<ThreadModeRawMutex, (usize, LedStatus), 4>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum MessageError {
+    TopicTooLong,
+    IdTooLong,
+    DataTooLong,
+    PayloadTooLong,
+    PayloadNotUtf8,
+}
+
+impl ChannelMessage {
+    pub fn new(topic: &str, id: &str, data: &str, payload: &str) -> Result<Self, MessageError> {
+        let mut message = ChannelMessage {
+            topic: String::new(),
+            id: String::new(),
+            data: String::new(),
+            payload: String::new(),
+        };
+        message
+            .topic
+            .push_str(topic)
+            .map_err(|_| MessageError::TopicTooLong)?;
+        message
+            .id
+            .push_str(id)
+            .map_err(|_| MessageError::IdTooLong)?;
+        message
+            .data
+            .push_str(data)
+            .map_err(|_| MessageError::DataTooLong)?;
+        message
+            .payload
+            .push_str(payload)
+            .map_err(|_| MessageError::PayloadTooLong)?;
+        Ok(message)
+    }
+
+    /// Build a message from the part of an MQTT topic following `{prefix}/{chip_id}/`,
+    /// e.g. `led/1/color`, and the raw MQTT payload.
+    pub fn from_mqtt(subtopic: &str, payload: &[u8]) -> Result<Self, MessageError> {
+        let mut parts = subtopic.splitn(3, '/');
+        let topic = parts.next().unwrap_or_default();
+        let id = parts.next().unwrap_or_default();
+        let data = parts.next().unwrap_or_default();
+        let payload = core::str::from_utf8(payload).map_err(|_| MessageError::PayloadNotUtf8)?;
+        Self::new(topic, id, data, payload)
+    }
+}
+
+const IDENTIFY_PERIOD: Duration = Duration::from_millis(250);
+
+/// Applies incoming messages to the leds, driving `signals`, and reports every
+/// effective change on `states` so it can be published back. Changes are also offered to
+/// `mirror`, which drops them while it is full.
+///
+/// An `identify` message flashes every led for the number of seconds in its payload, up to
+/// 255 and 5 if empty, then restores them.
+pub async fn message_parser<const N: usize>(
+    channel: &'static InputChannel,
+    signals: &'static [Signal<ThreadModeRawMutex, LedStatus>; N],
+    states: &'static [Signal<ThreadModeRawMutex, LedStatus>; N],
+    mirror: Option<&'static LedStateChannel>,
+) -> ! {
+    let mut leds = [LedStatus {
+        anim: Anim::None,
+        color: None,
+        power: None,
+    }; N];
+    for (state, led) in states.iter().zip(leds.iter()) {
+        state.signal(*led);
+    }
+    // end of the identification, and whether the leds are currently lit
+    let mut identify: Option<(Instant, bool)> = None;
+    loop {
+        let message = match identify {
+            None => channel.receive().await,
+            Some((end, lit)) => match select(channel.receive(), Timer::after(IDENTIFY_PERIOD)).await {
+                Either::First(message) => message,
+                Either::Second(()) => {
+                    if Instant::now() >= end {
+                        identify = None;
+                        for (signal, led) in signals.iter().zip(leds.iter()) {
+                            signal.signal(*led);
+                        }
+                    } else {
+                        identify = Some((end, !lit));
+                        let color = if lit { Color::off() } else { Color::new(255, 255, 255) };
+                        for signal in signals.iter() {
+                            signal.signal(LedStatus {
+                                color: Some(color),
+                                power: None,
+                                anim: Anim::None,
+                            });
+                        }
+                    }
+                    continue;
+                }
+            },
+        };
+        let ChannelMessage {
+            topic,
+            id,
+            data,
+            payload,
+        } = message;
+        if topic == "identify" {
+            let seconds: u8 = if payload.is_empty() {
+                5
+            } else if let Ok(seconds) = payload.parse() {
+                seconds
+            } else {
+                log_warn!("invalid identify duration : {}", payload);
+                telemetry::record_parse_error();
+                continue;
+            };
+            info!("identifying for {} s", seconds);
+            identify = Some((Instant::now() + Duration::from_secs(seconds.into()), false));
+            continue;
+        }
+        let id: usize = if let Ok(v) = id.parse() {
+            v
+        } else {
+            log_warn!("invalid id : {}", id);
+            telemetry::record_parse_error();
+            continue;
+        };
+        match topic.as_str() {
+            "led" => {
+                let led = if id == 0 || id > N {
+                    log_warn!("no led with id {}", id);
+                    telemetry::record_parse_error();
+                    continue;
+                } else {
+                    &mut leds[id - 1]
+                };
+                let previous = *led;
+                match data.as_str() {
+                    "color" => {
+                        let len = payload.chars().count();
+                        if len == 0 {
+                            led.color = None;
+                        } else if len == 1 {
+                            match payload.chars().nth(0) {
+                                None => core::unreachable!("length has been checked to be 1"),
+                                Some('R') | Some('r') => led.color = Some(Color::red()),
+                                Some('G') | Some('g') => led.color = Some(Color::green()),
+                                Some('B') | Some('b') => led.color = Some(Color::blue()),
+                                Some('0') | Some('O') | Some('o') => led.color = Some(Color::off()),
+                                Some(x) => {
+                                    log_warn!("unknown color {}", x);
+                                    telemetry::record_parse_error();
+                                }
+                            }
+                        } else if len == 4 && payload.starts_with("#") {
+                            let (r, g, b) = payload
+                                .chars()
+                                .skip(1)
+                                .map(|s| s.to_digit(16).unwrap_or_default() << 4)
+                                .collect_tuple()
+                                .unwrap();
+                            led.color = Some(Color::new(r as u8, g as u8, b as u8));
+                        } else {
+                            log_warn!("can not understand message : {}", payload);
+                            telemetry::record_parse_error();
+                            continue;
+                        }
+                    }
+                    "power" => {
+                        let len = payload.chars().count();
+                        if len == 0 {
+                            led.power = None;
+                        } else {
+                            led.power = payload.parse().ok();
+                        }
+                    }
+                    "anim" => match Anim::from_name(payload.as_str()) {
+                        _ if payload.is_empty() => led.anim = Anim::None,
+                        Some(anim) => led.anim = anim,
+                        None => {
+                            log_warn!("unknown animation : {}", payload);
+                            telemetry::record_parse_error();
+                            continue;
+                        }
+                    },
+                    "set" => {
+                        if let Err(e) =
+                            LedCommand::parse(&payload).and_then(|command| command.apply(led))
+                        {
+                            log_warn!("invalid json command : {:?}", e);
+                            telemetry::record_parse_error();
+                            continue;
+                        }
+                    }
+                    _ => {
+                        log_warn!("invalid data source : {}", data);
+                        telemetry::record_parse_error();
+                        continue;
+                    }
+                }
+                signals[id - 1].signal(led.clone());
+                if *led != previous {
+                    states[id - 1].signal(*led);
+                    if let Some(mirror) = mirror {
+                        let _ = mirror.try_send((id, *led));
+                    }
+                }
+            }
+            _ => {
+                log_warn!("topic unknown : {}", topic);
+                telemetry::record_parse_error();
+            }
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod buttons;
+pub mod messages;
@@ -1,3 +1,14 @@
 #![no_std]
+pub mod ble;
+pub mod board;
+pub mod clock;
+pub mod config;
+pub mod firmware;
 pub mod input;
+pub mod logging;
+pub mod mqtt;
+pub mod net;
 pub mod output;
+pub mod schedule;
+pub mod storage;
+pub mod telemetry;
@@ -0,0 +1,201 @@
+//! Log messages forwarded to `{prefix}/{chip_id}/log`, on top of the defmt output.
+//!
+//! defmt only sends format string indices over RTT, so forwarded messages are formatted on the
+//! device with `core::fmt` by the [`log_error!`], [`log_warn!`] and [`log_info!`] macros, then
+//! logged through defmt as plain strings. Forwarding is limited to [`RATE_LIMIT`] messages per
+//! [`RATE_WINDOW`], the level is set by publishing `error`, `warn`, `info` or `off` to
+//! `{prefix}/{chip_id}/log/level`.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+        }
+    }
+}
+
+pub const MAX_MESSAGE: usize = 96;
+pub const RATE_LIMIT: u8 = 10;
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct LogRecord {
+    pub level: Level,
+    pub uptime_ms: u64,
+    pub message: String<MAX_MESSAGE>,
+}
+
+/// Records waiting for the broker, read by the MQTT session.
+pub static LOG_CHANNEL: Channel<ThreadModeRawMutex, LogRecord, 8> = Channel::new();
+
+#[derive(Clone, Copy)]
+struct ForwardState {
+    /// Most verbose level forwarded, `None` when forwarding is off.
+    level: Option<Level>,
+    window_start: Instant,
+    sent: u8,
+    /// Records dropped by the rate limit or a full channel since the last one forwarded.
+    dropped: u32,
+}
+
+static STATE: Mutex<ThreadModeRawMutex, Cell<ForwardState>> = Mutex::new(Cell::new(ForwardState {
+    level: Some(Level::Warn),
+    window_start: Instant::from_ticks(0),
+    sent: 0,
+    dropped: 0,
+}));
+
+/// Handles a `log/level` payload.
+pub fn on_level_message(payload: &[u8]) -> bool {
+    let level = match payload {
+        b"error" => Some(Level::Error),
+        b"warn" => Some(Level::Warn),
+        b"info" => Some(Level::Info),
+        b"off" => None,
+        _ => return false,
+    };
+    defmt::info!("forwarded log level : {}", level);
+    STATE.lock(|state| {
+        let mut current = state.get();
+        current.level = level;
+        state.set(current);
+    });
+    true
+}
+
+/// Keeps what fits of the formatted message.
+struct Truncating<'a>(&'a mut String<MAX_MESSAGE>);
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.0.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Logs through defmt, and forwards the message if its level and the rate limit allow it.
+/// Use the macros rather than calling this directly.
+pub fn log(level: Level, args: core::fmt::Arguments) {
+    let mut message = String::new();
+    let _ = Truncating(&mut message).write_fmt(args);
+    match level {
+        Level::Error => defmt::error!("{=str}", message.as_str()),
+        Level::Warn => defmt::warn!("{=str}", message.as_str()),
+        Level::Info => defmt::info!("{=str}", message.as_str()),
+    }
+    let now = Instant::now();
+    let forward = STATE.lock(|state| {
+        let mut current = state.get();
+        if current.level.map_or(true, |max| level > max) {
+            return false;
+        }
+        if now - current.window_start > RATE_WINDOW {
+            current.window_start = now;
+            current.sent = 0;
+        }
+        let forward = current.sent < RATE_LIMIT;
+        if forward {
+            current.sent += 1;
+        } else {
+            current.dropped += 1;
+        }
+        state.set(current);
+        forward
+    });
+    if !forward {
+        return;
+    }
+    let record = LogRecord {
+        level,
+        uptime_ms: now.as_millis(),
+        message,
+    };
+    if LOG_CHANNEL.try_send(record).is_err() {
+        STATE.lock(|state| {
+            let mut current = state.get();
+            current.dropped += 1;
+            state.set(current);
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    level: &'a str,
+    uptime_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unix_ms: Option<u64>,
+    message: &'a str,
+    /// Messages lost before this one.
+    #[serde(skip_serializing_if = "is_zero")]
+    dropped: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+impl LogRecord {
+    /// Writes the JSON payload published for the record.
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize, serde_json_core::ser::Error> {
+        let dropped = STATE.lock(|state| {
+            let mut current = state.get();
+            let dropped = current.dropped;
+            current.dropped = 0;
+            state.set(current);
+            dropped
+        });
+        let unix_ms = crate::clock::now()
+            .map(|now| now.unix_ms - (Instant::now().as_millis() - self.uptime_ms));
+        let entry = LogEntry {
+            level: self.level.name(),
+            uptime_ms: self.uptime_ms,
+            unix_ms,
+            message: &self.message,
+            dropped,
+        };
+        serde_json_core::to_slice(&entry, buffer)
+    }
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
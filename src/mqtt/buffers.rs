@@ -0,0 +1,55 @@
+//! MQTT client buffer sizes, selected per build with the `mqtt-buffers-small` and
+//! `mqtt-buffers-large` features. The default fits discovery configs and json commands.
+
+#[cfg(all(feature = "mqtt-buffers-small", feature = "mqtt-buffers-large"))]
+compile_error!("features `mqtt-buffers-small` and `mqtt-buffers-large` are mutually exclusive");
+
+#[cfg(feature = "mqtt-buffers-small")]
+pub const SEND_BUFFER_SIZE: usize = 256;
+#[cfg(feature = "mqtt-buffers-small")]
+pub const RECV_BUFFER_SIZE: usize = 128;
+
+#[cfg(feature = "mqtt-buffers-large")]
+pub const SEND_BUFFER_SIZE: usize = 4096;
+#[cfg(feature = "mqtt-buffers-large")]
+pub const RECV_BUFFER_SIZE: usize = 2048;
+
+#[cfg(not(any(feature = "mqtt-buffers-small", feature = "mqtt-buffers-large")))]
+pub const SEND_BUFFER_SIZE: usize = 1024;
+#[cfg(not(any(feature = "mqtt-buffers-small", feature = "mqtt-buffers-large")))]
+pub const RECV_BUFFER_SIZE: usize = 512;
+
+/// Room for the fixed header, topic length, packet id and property length of a publish.
+const PUBLISH_OVERHEAD: usize = 10;
+
+pub struct MqttBuffers {
+    pub send: [u8; SEND_BUFFER_SIZE],
+    pub recv: [u8; RECV_BUFFER_SIZE],
+}
+
+impl MqttBuffers {
+    pub const fn new() -> Self {
+        Self {
+            send: [0; SEND_BUFFER_SIZE],
+            recv: [0; RECV_BUFFER_SIZE],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PacketTooLarge {
+    pub size: usize,
+    pub capacity: usize,
+}
+
+pub fn check_publish(topic: &str, payload: &[u8]) -> Result<(), PacketTooLarge> {
+    let size = topic.len() + payload.len() + PUBLISH_OVERHEAD;
+    if size > SEND_BUFFER_SIZE {
+        Err(PacketTooLarge {
+            size,
+            capacity: SEND_BUFFER_SIZE,
+        })
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! MQTT 5 client over any `embedded-io-async` connection.
+//!
+//! Nothing but the CONNACK is waited for: acknowledgements of publishes come back from
+//! [`MqttClient::receive`] as [`Event`]s, so messages the broker sends in between are never
+//! mistaken for them. Incoming QoS1 and QoS2 publishes are acknowledged by the client, and their
+//! redeliveries dropped, see [`DuplicateFilter`].
+//!
+//! The broker keeps the session for [`SESSION_EXPIRY_S`] after a disconnection, and the
+//! [`SessionState`] keeps our side of it, so publishes cut off by a disconnection complete on
+//! the next connection. The first connection after boot starts a clean session.
+
+use embedded_io_async::{Error as _, ErrorKind, Read, ReadExactError, Write};
+
+use super::delivery::{DuplicateFilter, Qos, RECEIVE_MAXIMUM};
+use super::packet::{self, Ack, Connect, Message, Packet, Publish};
+
+/// Also kept alive by the telemetry, published every minute.
+pub const KEEP_ALIVE_S: u16 = 90;
+
+pub const SESSION_EXPIRY_S: u32 = 10 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Error {
+    Network(ErrorKind),
+    /// The broker closed the connection.
+    Closed,
+    /// A packet does not fit the send or receive buffer.
+    BufferTooSmall,
+    /// The broker sent something which is not MQTT 5.
+    Protocol,
+    /// The broker refused the connection, or disconnected us, with this reason code.
+    Refused(u8),
+}
+
+/// What the broker sent, other than acknowledgements handled by the client.
+pub enum Event<'b> {
+    Message(Message<'b>),
+    /// An outgoing QoS2 publish was received by the broker, and released.
+    Released(u16),
+    /// An outgoing publish completed, with a PUBACK at QoS1 or a PUBCOMP at QoS2.
+    Delivered(u16),
+    /// The broker refused an outgoing publish, with this reason code.
+    Refused(u16, u8),
+}
+
+/// Client side of the session, kept across connections.
+pub struct SessionState {
+    next_packet_id: u16,
+    duplicates: DuplicateFilter,
+    /// Whether a session was started since boot.
+    started: bool,
+}
+
+impl SessionState {
+    pub const fn new() -> Self {
+        Self {
+            next_packet_id: 1,
+            duplicates: DuplicateFilter::new(),
+            started: false,
+        }
+    }
+}
+
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+}
+
+pub struct MqttClient<'a, T> {
+    connection: T,
+    send: &'a mut [u8],
+    recv: &'a mut [u8],
+    session: &'a mut SessionState,
+}
+
+/// What [`MqttClient::handle`] did with a packet.
+enum Handled {
+    Deliver,
+    Event(Event<'static>),
+    Nothing,
+}
+
+impl<'a, T: Read + Write> MqttClient<'a, T> {
+    pub fn new(connection: T, send: &'a mut [u8], recv: &'a mut [u8], session: &'a mut SessionState) -> Self {
+        Self {
+            connection,
+            send,
+            recv,
+            session,
+        }
+    }
+
+    /// Connects, returning whether the broker still had the session.
+    pub async fn connect(&mut self, options: &ConnectOptions<'_>) -> Result<bool, Error> {
+        let connect = Connect {
+            client_id: options.client_id,
+            username: options.username,
+            password: options.password,
+            keep_alive_s: KEEP_ALIVE_S,
+            clean_start: !self.session.started,
+            session_expiry_s: SESSION_EXPIRY_S,
+            receive_maximum: RECEIVE_MAXIMUM as u16,
+            maximum_packet_size: self.recv.len() as u32,
+        };
+        let packet = connect.encode(self.send).map_err(|_| Error::BufferTooSmall)?;
+        send(&mut self.connection, packet).await?;
+        let (first, length) = self.read_packet().await?;
+        let session_present = match Packet::decode(first, &self.recv[..length]) {
+            Ok(Packet::Connack {
+                session_present,
+                reason: 0,
+            }) => session_present,
+            Ok(Packet::Connack { reason, .. }) => return Err(Error::Refused(reason)),
+            _ => return Err(Error::Protocol),
+        };
+        if !session_present {
+            self.session.duplicates.clear();
+        }
+        self.session.started = true;
+        Ok(session_present)
+    }
+
+    /// Next packet id, for a publish or a subscription.
+    pub fn packet_id(&mut self) -> u16 {
+        let id = self.session.next_packet_id;
+        self.session.next_packet_id = id.wrapping_add(1).max(1);
+        id
+    }
+
+    pub async fn publish(&mut self, publish: &Publish<'_>) -> Result<(), Error> {
+        let packet = publish.encode(self.send).map_err(|_| Error::BufferTooSmall)?;
+        send(&mut self.connection, packet).await
+    }
+
+    /// Sends the PUBREL of a QoS2 publish again, after a reconnection.
+    pub async fn release(&mut self, packet_id: u16) -> Result<(), Error> {
+        send(&mut self.connection, &Ack::Pubrel.encode(packet_id)).await
+    }
+
+    /// Subscribes without waiting for the SUBACK, refusals being logged when it comes.
+    pub async fn subscribe(&mut self, filters: &[&str]) -> Result<(), Error> {
+        let packet_id = self.packet_id();
+        let packet = packet::subscribe(self.send, packet_id, filters).map_err(|_| Error::BufferTooSmall)?;
+        send(&mut self.connection, packet).await
+    }
+
+    pub async fn unsubscribe(&mut self, filters: &[&str]) -> Result<(), Error> {
+        let packet_id = self.packet_id();
+        let packet = packet::unsubscribe(self.send, packet_id, filters).map_err(|_| Error::BufferTooSmall)?;
+        send(&mut self.connection, packet).await
+    }
+
+    /// Waits for the next message or publish acknowledgement.
+    pub async fn receive(&mut self) -> Result<Event<'_>, Error> {
+        loop {
+            let (first, length) = self.read_packet().await?;
+            match self.handle(first, length).await? {
+                Handled::Deliver => match Packet::decode(first, &self.recv[..length]) {
+                    Ok(Packet::Publish(message)) => return Ok(Event::Message(message)),
+                    _ => defmt::unreachable!("decoded as a publish before"),
+                },
+                Handled::Event(event) => return Ok(event),
+                Handled::Nothing => {}
+            }
+        }
+    }
+
+    /// Answers a packet as the protocol requires.
+    async fn handle(&mut self, first: u8, length: usize) -> Result<Handled, Error> {
+        let packet = Packet::decode(first, &self.recv[..length]).map_err(|_| Error::Protocol)?;
+        let handled = match packet {
+            Packet::Publish(message) => {
+                let new = match message.qos {
+                    Qos::AtMostOnce => true,
+                    Qos::AtLeastOnce => {
+                        send(&mut self.connection, &Ack::Puback.encode(message.packet_id)).await?;
+                        self.session.duplicates.at_least_once(
+                            message.packet_id,
+                            message.dup,
+                            message.topic,
+                            message.payload,
+                        )
+                    }
+                    Qos::ExactlyOnce => {
+                        let new = self
+                            .session
+                            .duplicates
+                            .exactly_once(message.packet_id)
+                            .map_err(|_| Error::Protocol)?;
+                        send(&mut self.connection, &Ack::Pubrec.encode(message.packet_id)).await?;
+                        new
+                    }
+                };
+                if new {
+                    Handled::Deliver
+                } else {
+                    defmt::debug!("dropping redelivery of {} on {}", message.packet_id, message.topic);
+                    Handled::Nothing
+                }
+            }
+            Packet::Ack {
+                kind: Ack::Puback | Ack::Pubrec,
+                packet_id,
+                reason,
+            } if reason >= 0x80 => Handled::Event(Event::Refused(packet_id, reason)),
+            Packet::Ack {
+                kind: Ack::Puback | Ack::Pubcomp,
+                packet_id,
+                ..
+            } => Handled::Event(Event::Delivered(packet_id)),
+            Packet::Ack {
+                kind: Ack::Pubrec,
+                packet_id,
+                ..
+            } => {
+                send(&mut self.connection, &Ack::Pubrel.encode(packet_id)).await?;
+                Handled::Event(Event::Released(packet_id))
+            }
+            Packet::Ack {
+                kind: Ack::Pubrel,
+                packet_id,
+                ..
+            } => {
+                self.session.duplicates.release(packet_id);
+                send(&mut self.connection, &Ack::Pubcomp.encode(packet_id)).await?;
+                Handled::Nothing
+            }
+            Packet::Suback { packet_id, reasons } => {
+                for reason in reasons.iter().filter(|&&reason| reason >= 0x80) {
+                    defmt::warn!("subscription {} refused : {:x}", packet_id, reason);
+                }
+                Handled::Nothing
+            }
+            Packet::Unsuback | Packet::Pingresp => Handled::Nothing,
+            Packet::Disconnect { reason } => return Err(Error::Refused(reason)),
+            Packet::Connack { .. } => return Err(Error::Protocol),
+        };
+        Ok(handled)
+    }
+
+    /// Reads a packet body into the receive buffer, returning its first byte and length.
+    async fn read_packet(&mut self) -> Result<(u8, usize), Error> {
+        let first = self.read_byte().await?;
+        let mut length = 0;
+        for shift in [0, 7, 14, 21] {
+            let byte = self.read_byte().await?;
+            length |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                let body = self.recv.get_mut(..length).ok_or(Error::BufferTooSmall)?;
+                self.connection.read_exact(body).await.map_err(read_error)?;
+                return Ok((first, length));
+            }
+        }
+        Err(Error::Protocol)
+    }
+
+    async fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut byte = [0];
+        self.connection.read_exact(&mut byte).await.map_err(read_error)?;
+        Ok(byte[0])
+    }
+}
+
+async fn send<T: Write>(connection: &mut T, packet: &[u8]) -> Result<(), Error> {
+    connection
+        .write_all(packet)
+        .await
+        .map_err(|e| Error::Network(e.kind()))?;
+    connection.flush().await.map_err(|e| Error::Network(e.kind()))
+}
+
+fn read_error<E: embedded_io_async::Error>(error: ReadExactError<E>) -> Error {
+    match error {
+        ReadExactError::UnexpectedEof => Error::Closed,
+        ReadExactError::Other(e) => Error::Network(e.kind()),
+    }
+}
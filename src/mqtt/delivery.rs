@@ -0,0 +1,254 @@
+//! Delivery guarantees on top of the MQTT client.
+//!
+//! Messages which must not be lost are sent at QoS1 or QoS2 from the [`Outbox`], one at a time,
+//! and kept there until the broker acknowledged them: a PUBACK at QoS1, a PUBCOMP at QoS2 after
+//! the PUBREC and PUBREL. The session is kept by the broker across reconnections, so an entry
+//! cut off by a disconnection is sent again with its packet id, and QoS2 ones are delivered
+//! exactly once.
+//!
+//! Incoming QoS1 and QoS2 publishes go through the [`DuplicateFilter`], so a redelivery is
+//! acknowledged again but not applied twice.
+
+use heapless::{Deque, String, Vec};
+
+use super::json::MAX_JSON_PAYLOAD;
+
+/// Incoming QoS1 and QoS2 publishes the broker may leave unacknowledged at once, advertised on
+/// connect.
+pub const RECEIVE_MAXIMUM: usize = 4;
+
+/// Incoming QoS1 publishes remembered to recognize their redeliveries.
+const RECENT_AT_LEAST_ONCE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Qos {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl Qos {
+    pub fn bits(self) -> u8 {
+        match self {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1,
+            Qos::ExactlyOnce => 2,
+        }
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Qos::AtMostOnce),
+            1 => Some(Qos::AtLeastOnce),
+            2 => Some(Qos::ExactlyOnce),
+            _ => None,
+        }
+    }
+}
+
+/// Where an outbox entry is in its exchange with the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Stage {
+    /// Not sent on this session yet.
+    Queued,
+    /// Sent, waiting for the PUBACK or PUBREC.
+    Published,
+    /// QoS2 only, the PUBREC came and the PUBREL was sent, waiting for the PUBCOMP.
+    Released,
+}
+
+pub struct OutboxEntry {
+    /// Outbox sequence number, for logs.
+    pub id: u16,
+    pub topic: String<64>,
+    pub payload: Vec<u8, MAX_JSON_PAYLOAD>,
+    pub qos: Qos,
+    pub retain: bool,
+    /// Times the publish was attempted without being acknowledged.
+    pub attempts: u8,
+    /// MQTT packet id, assigned when first published.
+    pub packet_id: u16,
+    pub stage: Stage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OutboxError {
+    Full,
+    TopicTooLong,
+    PayloadTooLong,
+}
+
+/// Publishes waiting for an acknowledgement, kept across reconnections.
+pub struct Outbox<const N: usize> {
+    entries: Deque<OutboxEntry, N>,
+    next_id: u16,
+}
+
+impl<const N: usize> Outbox<N> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Deque::new(),
+            next_id: 1,
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: Qos,
+        retain: bool,
+    ) -> Result<u16, OutboxError> {
+        if self.entries.is_full() {
+            return Err(OutboxError::Full);
+        }
+        let mut entry = OutboxEntry {
+            id: self.next_id,
+            topic: String::new(),
+            payload: Vec::new(),
+            qos,
+            retain,
+            attempts: 0,
+            packet_id: 0,
+            stage: Stage::Queued,
+        };
+        entry
+            .topic
+            .push_str(topic)
+            .map_err(|_| OutboxError::TopicTooLong)?;
+        entry
+            .payload
+            .extend_from_slice(payload)
+            .map_err(|_| OutboxError::PayloadTooLong)?;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+        let id = entry.id;
+        // checked above
+        let _ = self.entries.push_back(entry);
+        Ok(id)
+    }
+
+    /// Oldest unacknowledged entry, the only one in flight once published.
+    pub fn front_mut(&mut self) -> Option<&mut OutboxEntry> {
+        self.entries.front_mut()
+    }
+
+    /// Drops the oldest entry once it was delivered, or can not be.
+    pub fn ack(&mut self) -> Option<OutboxEntry> {
+        self.entries.pop_front()
+    }
+
+    fn in_flight(&mut self, packet_id: u16) -> Option<&mut OutboxEntry> {
+        self.entries
+            .front_mut()
+            .filter(|entry| entry.stage != Stage::Queued && entry.packet_id == packet_id)
+    }
+
+    /// The broker received the QoS2 entry in flight as `packet_id`, which was released.
+    pub fn released(&mut self, packet_id: u16) {
+        if let Some(entry) = self.in_flight(packet_id) {
+            entry.stage = Stage::Released;
+        }
+    }
+
+    /// Drops the entry in flight as `packet_id` once the broker delivered or refused it.
+    pub fn completed(&mut self, packet_id: u16) -> Option<OutboxEntry> {
+        self.in_flight(packet_id)?;
+        self.entries.pop_front()
+    }
+
+    /// Prepares the entry in flight for a new connection. When the broker did not keep the
+    /// session, a publish it did not acknowledge is sent again as a new one, and a QoS2 publish
+    /// it already received is considered delivered.
+    pub fn reconnected(&mut self, session_present: bool) {
+        if session_present {
+            return;
+        }
+        match self.entries.front().map(|entry| entry.stage) {
+            Some(Stage::Published) => {
+                if let Some(entry) = self.entries.front_mut() {
+                    entry.stage = Stage::Queued;
+                }
+            }
+            Some(Stage::Released) => {
+                self.entries.pop_front();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ReceiveMaximumExceeded;
+
+/// Tells redeliveries of incoming QoS1 and QoS2 publishes from new messages, by packet id.
+pub struct DuplicateFilter {
+    /// QoS2 publishes delivered, until the broker releases their packet id.
+    unreleased: Vec<u16, RECEIVE_MAXIMUM>,
+    /// Last QoS1 publishes delivered, with a fingerprint of their topic and payload.
+    recent: Deque<(u16, u32), RECENT_AT_LEAST_ONCE>,
+}
+
+impl DuplicateFilter {
+    pub const fn new() -> Self {
+        Self {
+            unreleased: Vec::new(),
+            recent: Deque::new(),
+        }
+    }
+
+    /// Whether a QoS1 publish is new. A redelivery has the DUP flag and the packet id of an
+    /// earlier delivery, the fingerprint telling it from a new message reusing that id.
+    pub fn at_least_once(&mut self, packet_id: u16, dup: bool, topic: &str, payload: &[u8]) -> bool {
+        let seen = (packet_id, fingerprint(topic, payload));
+        if dup && self.recent.iter().any(|&recent| recent == seen) {
+            return false;
+        }
+        if self.recent.is_full() {
+            self.recent.pop_front();
+        }
+        let _ = self.recent.push_back(seen);
+        true
+    }
+
+    /// Whether a QoS2 publish is new, that is its packet id is not waiting for a PUBREL.
+    pub fn exactly_once(&mut self, packet_id: u16) -> Result<bool, ReceiveMaximumExceeded> {
+        if self.unreleased.contains(&packet_id) {
+            return Ok(false);
+        }
+        self.unreleased
+            .push(packet_id)
+            .map_err(|_| ReceiveMaximumExceeded)?;
+        Ok(true)
+    }
+
+    /// The broker sent the PUBREL of a QoS2 publish, its packet id can be reused.
+    pub fn release(&mut self, packet_id: u16) {
+        self.unreleased.retain(|&id| id != packet_id);
+    }
+
+    /// Forgets all deliveries, when the broker did not keep the session.
+    pub fn clear(&mut self) {
+        self.unreleased.clear();
+        self.recent.clear();
+    }
+}
+
+/// FNV-1a hash of a message.
+fn fingerprint(topic: &str, payload: &[u8]) -> u32 {
+    topic
+        .as_bytes()
+        .iter()
+        .chain(&[0])
+        .chain(payload)
+        .fold(0x811c_9dc5, |hash, &byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        })
+}
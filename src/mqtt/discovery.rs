@@ -0,0 +1,72 @@
+//! Home Assistant MQTT discovery configs, published retained under `homeassistant/`.
+
+use core::fmt::{Result, Write};
+
+use crate::input::buttons::ButtonEvent;
+use crate::output::leds::Anim;
+
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+fn write_device(out: &mut impl Write, chip_id: &str) -> Result {
+    write!(
+        out,
+        "\"device\":{{\"identifiers\":[\"{chip_id}\"],\"name\":\"{chip_id}\",\"manufacturer\":\"mqtt_pico\",\"model\":\"Raspberry Pi Pico W\"}}"
+    )
+}
+
+pub fn light_topic(out: &mut impl Write, chip_id: &str, led: usize) -> Result {
+    write!(out, "{DISCOVERY_PREFIX}/light/{chip_id}_led{led}/config")
+}
+
+/// JSON schema light, commanded through `led/<id>/set` and reporting on `led/<id>/state`.
+pub fn light_config(out: &mut impl Write, prefix: &str, chip_id: &str, led: usize) -> Result {
+    write!(
+        out,
+        "{{\"name\":\"LED {led}\",\"unique_id\":\"{chip_id}_led{led}\",\"schema\":\"json\",\
+        \"command_topic\":\"{prefix}/{chip_id}/led/{led}/set\",\
+        \"state_topic\":\"{prefix}/{chip_id}/led/{led}/state\",\
+        \"supported_color_modes\":[\"rgb\"],\"brightness\":true,\"effect\":true,\"effect_list\":["
+    )?;
+    for (i, anim) in Anim::ALL.iter().enumerate() {
+        if i > 0 {
+            out.write_char(',')?;
+        }
+        write!(out, "\"{anim}\"")?;
+    }
+    out.write_str("],")?;
+    write_device(out, chip_id)?;
+    out.write_char('}')
+}
+
+pub fn button_trigger_topic(
+    out: &mut impl Write,
+    chip_id: &str,
+    button: usize,
+    event: ButtonEvent,
+) -> Result {
+    write!(
+        out,
+        "{DISCOVERY_PREFIX}/device_automation/{chip_id}/button{button}_{event}/config"
+    )
+}
+
+/// Device trigger firing on the `event` payload of `{prefix}/{chip_id}/button/<id>`.
+pub fn button_trigger_config(
+    out: &mut impl Write,
+    prefix: &str,
+    chip_id: &str,
+    button: usize,
+    event: ButtonEvent,
+) -> Result {
+    let kind = match event {
+        ButtonEvent::Press => "button_short_press",
+        ButtonEvent::Release => "button_short_release",
+    };
+    write!(
+        out,
+        "{{\"automation_type\":\"trigger\",\"topic\":\"{prefix}/{chip_id}/button/{button}\",\
+        \"payload\":\"{event}\",\"type\":\"{kind}\",\"subtype\":\"button_{button}\","
+    )?;
+    write_device(out, chip_id)?;
+    out.write_char('}')
+}
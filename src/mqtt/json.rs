@@ -0,0 +1,113 @@
+//! Home Assistant JSON schema payloads for `led/<id>/set` and `led/<id>/state`, e.g.
+//! `{"state":"ON","color":{"r":255,"g":0,"b":0},"brightness":128,"effect":"pulse"}`.
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::leds::{Anim, Color, LedStatus};
+
+/// Largest JSON document accepted on `led/<id>/set`, and largest state document produced.
+pub const MAX_JSON_PAYLOAD: usize = 128;
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub struct JsonColor {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Deserialize, Serialize, Default)]
+#[serde(default)]
+pub struct LedCommand<'a> {
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub state: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<JsonColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<u8>,
+    #[serde(borrow, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<&'a str>,
+    /// Accepted for compatibility, the leds switch immediately.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transition: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct LedState<'a> {
+    pub state: &'a str,
+    pub color_mode: &'a str,
+    pub color: JsonColor,
+    pub brightness: u8,
+    pub effect: &'a str,
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    Parse(serde_json_core::de::Error),
+    UnknownState,
+    UnknownEffect,
+    Serialize(serde_json_core::ser::Error),
+}
+
+impl<'a> LedCommand<'a> {
+    pub fn parse(payload: &'a str) -> Result<Self, JsonError> {
+        serde_json_core::from_str(payload)
+            .map(|(command, _)| command)
+            .map_err(JsonError::Parse)
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize, JsonError> {
+        serde_json_core::to_slice(self, buffer).map_err(JsonError::Serialize)
+    }
+
+    /// Applies the command on top of `led`, leaving it untouched on error.
+    pub fn apply(&self, led: &mut LedStatus) -> Result<(), JsonError> {
+        let mut next = *led;
+        if let Some(JsonColor { r, g, b }) = self.color {
+            next.color = Some(Color::new(r, g, b));
+        }
+        if let Some(brightness) = self.brightness {
+            next.power = Some(brightness);
+        }
+        if let Some(effect) = self.effect {
+            next.anim = Anim::from_name(effect).ok_or(JsonError::UnknownEffect)?;
+        }
+        match self.state {
+            None => {}
+            Some("ON") => {
+                if !next.is_on() {
+                    next.power = Some(u8::MAX);
+                }
+            }
+            Some("OFF") => next.power = Some(0),
+            Some(_) => return Err(JsonError::UnknownState),
+        }
+        *led = next;
+        Ok(())
+    }
+}
+
+impl<'a> LedState<'a> {
+    pub fn new(led: &LedStatus) -> Self {
+        let Color { red, green, blue } = led.color.unwrap_or(Color::new(255, 255, 255));
+        let brightness = match (led.color, led.power) {
+            (_, Some(power)) => power,
+            (Some(_), None) => u8::MAX,
+            (None, None) => 0,
+        };
+        LedState {
+            state: if led.is_on() { "ON" } else { "OFF" },
+            color_mode: "rgb",
+            color: JsonColor {
+                r: red,
+                g: green,
+                b: blue,
+            },
+            brightness,
+            effect: led.anim.name(),
+        }
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize, JsonError> {
+        serde_json_core::to_slice(self, buffer).map_err(JsonError::Serialize)
+    }
+}
@@ -0,0 +1,111 @@
+pub mod buffers;
+pub mod client;
+pub mod delivery;
+pub mod discovery;
+pub mod json;
+pub mod packet;
+pub mod rpc;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+
+use embedded_io_async::{Read, Write};
+
+use buffers::PacketTooLarge;
+use client::{Error, MqttClient};
+use delivery::{Outbox, Qos, Stage};
+use packet::Publish;
+
+#[derive(Debug)]
+pub enum PublishError {
+    TooLarge(PacketTooLarge),
+    Mqtt(Error),
+}
+
+/// Publishes at QoS0, refusing up front what would not fit the send buffer. What must be
+/// acknowledged goes through the [`Outbox`].
+pub async fn publish<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> Result<(), PublishError> {
+    buffers::check_publish(topic, payload).map_err(PublishError::TooLarge)?;
+    let publish = Publish {
+        topic,
+        payload,
+        qos: Qos::AtMostOnce,
+        retain,
+        dup: false,
+        packet_id: 0,
+    };
+    client.publish(&publish).await.map_err(PublishError::Mqtt)
+}
+
+/// Publishes the oldest outbox entry unless it is already in flight, QoS0 entries being
+/// dropped once sent. Entries that can never be sent are dropped.
+pub async fn flush_outbox<T: Read + Write, const N: usize>(
+    client: &mut MqttClient<'_, T>,
+    outbox: &mut Outbox<N>,
+) -> Result<(), Error> {
+    while let Some(entry) = outbox.front_mut() {
+        if entry.stage != Stage::Queued {
+            return Ok(());
+        }
+        if let Err(e) = buffers::check_publish(&entry.topic, &entry.payload) {
+            defmt::warn!("dropping message {} : {}", entry.id, e);
+            outbox.ack();
+            continue;
+        }
+        entry.attempts = entry.attempts.saturating_add(1);
+        if entry.attempts > 1 {
+            defmt::debug!("redelivering message {} on {}", entry.id, entry.topic.as_str());
+        }
+        entry.packet_id = client.packet_id();
+        let publish = Publish {
+            topic: &entry.topic,
+            payload: &entry.payload,
+            qos: entry.qos,
+            retain: entry.retain,
+            dup: false,
+            packet_id: entry.packet_id,
+        };
+        client.publish(&publish).await?;
+        if entry.qos == Qos::AtMostOnce {
+            outbox.ack();
+        } else {
+            entry.stage = Stage::Published;
+        }
+    }
+    Ok(())
+}
+
+/// Picks up the exchange of the entry in flight where the previous connection left it, then
+/// publishes what is queued.
+pub async fn resume_outbox<T: Read + Write, const N: usize>(
+    client: &mut MqttClient<'_, T>,
+    outbox: &mut Outbox<N>,
+    session_present: bool,
+) -> Result<(), Error> {
+    outbox.reconnected(session_present);
+    if let Some(entry) = outbox.front_mut() {
+        match entry.stage {
+            Stage::Queued => {}
+            Stage::Published => {
+                entry.attempts = entry.attempts.saturating_add(1);
+                defmt::debug!("redelivering message {} on {}", entry.id, entry.topic.as_str());
+                let publish = Publish {
+                    topic: &entry.topic,
+                    payload: &entry.payload,
+                    qos: entry.qos,
+                    retain: entry.retain,
+                    dup: true,
+                    packet_id: entry.packet_id,
+                };
+                client.publish(&publish).await?;
+            }
+            Stage::Released => client.release(entry.packet_id).await?,
+        }
+    }
+    flush_outbox(client, outbox).await
+}
@@ -0,0 +1,352 @@
+//! Encoding and decoding of the MQTT 5 packets exchanged by [`client`](super::client).
+//!
+//! Outgoing packets carry no properties besides those of CONNECT, and the properties of incoming
+//! packets are skipped.
+
+use super::delivery::Qos;
+
+/// Room kept in front of a packet body for its fixed header.
+const HEADER_ROOM: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct BufferFull;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Malformed;
+
+/// Writes a packet body into a buffer, then its fixed header in front of it.
+struct Encoder<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Encoder<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Self {
+            buf,
+            len: HEADER_ROOM,
+        }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), BufferFull> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(BufferFull)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), BufferFull> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), BufferFull> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), BufferFull> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn varint(&mut self, value: usize) -> Result<(), BufferFull> {
+        let mut bytes = [0; 4];
+        let len = encode_varint(value, &mut bytes);
+        self.bytes(&bytes[..len])
+    }
+
+    /// UTF-8 string or binary data, prefixed by its length.
+    fn string(&mut self, data: &[u8]) -> Result<(), BufferFull> {
+        let len = u16::try_from(data.len()).map_err(|_| BufferFull)?;
+        self.u16(len)?;
+        self.bytes(data)
+    }
+
+    /// The whole packet, `first` being the packet type and flags byte.
+    fn finish(self, first: u8) -> &'b [u8] {
+        let Encoder { buf, len } = self;
+        let mut length = [0; 4];
+        let length_len = encode_varint(len - HEADER_ROOM, &mut length);
+        let start = HEADER_ROOM - 1 - length_len;
+        buf[start] = first;
+        buf[start + 1..HEADER_ROOM].copy_from_slice(&length[..length_len]);
+        &buf[start..len]
+    }
+}
+
+/// Variable byte integer, returning how many bytes it took.
+fn encode_varint(mut value: usize, out: &mut [u8; 4]) -> usize {
+    let mut len = 0;
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out[len] = byte;
+        len += 1;
+        if value == 0 || len == out.len() {
+            return len;
+        }
+    }
+}
+
+/// Reads the fields of a packet body in order.
+struct Decoder<'b> {
+    data: &'b [u8],
+}
+
+impl<'b> Decoder<'b> {
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8], Malformed> {
+        if len > self.data.len() {
+            return Err(Malformed);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, Malformed> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Malformed> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn varint(&mut self) -> Result<usize, Malformed> {
+        let mut value = 0;
+        for shift in [0, 7, 14, 21] {
+            let byte = self.u8()?;
+            value |= usize::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Malformed)
+    }
+
+    fn string(&mut self) -> Result<&'b str, Malformed> {
+        let len = self.u16()?;
+        core::str::from_utf8(self.bytes(len.into())?).map_err(|_| Malformed)
+    }
+
+    fn skip_properties(&mut self) -> Result<(), Malformed> {
+        let len = self.varint()?;
+        self.bytes(len).map(|_| ())
+    }
+
+    /// Reason code of an acknowledgement, success when the broker left it out.
+    fn reason(&mut self) -> Result<u8, Malformed> {
+        if self.data.is_empty() {
+            Ok(0)
+        } else {
+            self.u8()
+        }
+    }
+}
+
+pub struct Connect<'a> {
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub keep_alive_s: u16,
+    /// Whether the broker should drop the session it kept for us.
+    pub clean_start: bool,
+    pub session_expiry_s: u32,
+    /// Incoming QoS1 and QoS2 publishes the broker may leave unacknowledged at once.
+    pub receive_maximum: u16,
+    pub maximum_packet_size: u32,
+}
+
+impl Connect<'_> {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], BufferFull> {
+        let mut out = Encoder::new(buf);
+        out.string(b"MQTT")?;
+        out.u8(5)?;
+        let mut flags = 0;
+        if self.username.is_some() {
+            flags |= 0x80;
+        }
+        if self.password.is_some() {
+            flags |= 0x40;
+        }
+        if self.clean_start {
+            flags |= 0x02;
+        }
+        out.u8(flags)?;
+        out.u16(self.keep_alive_s)?;
+        // session expiry interval, receive maximum and maximum packet size
+        out.varint(5 + 3 + 5)?;
+        out.u8(0x11)?;
+        out.u32(self.session_expiry_s)?;
+        out.u8(0x21)?;
+        out.u16(self.receive_maximum)?;
+        out.u8(0x27)?;
+        out.u32(self.maximum_packet_size)?;
+        out.string(self.client_id.as_bytes())?;
+        if let Some(username) = self.username {
+            out.string(username.as_bytes())?;
+        }
+        if let Some(password) = self.password {
+            out.string(password.as_bytes())?;
+        }
+        Ok(out.finish(0x10))
+    }
+}
+
+pub struct Publish<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: Qos,
+    pub retain: bool,
+    /// Set when sending again a publish the broker may already have.
+    pub dup: bool,
+    /// Ignored at QoS0.
+    pub packet_id: u16,
+}
+
+impl Publish<'_> {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], BufferFull> {
+        let mut out = Encoder::new(buf);
+        out.string(self.topic.as_bytes())?;
+        if self.qos != Qos::AtMostOnce {
+            out.u16(self.packet_id)?;
+        }
+        out.varint(0)?;
+        out.bytes(self.payload)?;
+        let first = 0x30 | u8::from(self.dup) << 3 | self.qos.bits() << 1 | u8::from(self.retain);
+        Ok(out.finish(first))
+    }
+}
+
+/// SUBSCRIBE for `filters`, up to QoS2. With no local set, the broker does not send back what
+/// the client publishes itself.
+pub fn subscribe<'b>(buf: &'b mut [u8], packet_id: u16, filters: &[&str]) -> Result<&'b [u8], BufferFull> {
+    let mut out = Encoder::new(buf);
+    out.u16(packet_id)?;
+    out.varint(0)?;
+    for filter in filters {
+        out.string(filter.as_bytes())?;
+        // maximum QoS 2 and no local
+        out.u8(0x02 | 0x04)?;
+    }
+    Ok(out.finish(0x82))
+}
+
+pub fn unsubscribe<'b>(buf: &'b mut [u8], packet_id: u16, filters: &[&str]) -> Result<&'b [u8], BufferFull> {
+    let mut out = Encoder::new(buf);
+    out.u16(packet_id)?;
+    out.varint(0)?;
+    for filter in filters {
+        out.string(filter.as_bytes())?;
+    }
+    Ok(out.finish(0xa2))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Ack {
+    Puback,
+    Pubrec,
+    Pubrel,
+    Pubcomp,
+}
+
+impl Ack {
+    /// The acknowledgement, with a success reason code left out.
+    pub fn encode(self, packet_id: u16) -> [u8; 4] {
+        let first = match self {
+            Ack::Puback => 0x40,
+            Ack::Pubrec => 0x50,
+            Ack::Pubrel => 0x62,
+            Ack::Pubcomp => 0x70,
+        };
+        let [high, low] = packet_id.to_be_bytes();
+        [first, 2, high, low]
+    }
+}
+
+/// An incoming PUBLISH.
+pub struct Message<'b> {
+    pub topic: &'b str,
+    pub payload: &'b [u8],
+    pub qos: Qos,
+    /// Set by the broker on a redelivery.
+    pub dup: bool,
+    /// 0 at QoS0.
+    pub packet_id: u16,
+}
+
+/// Packets the broker sends.
+pub enum Packet<'b> {
+    Connack { session_present: bool, reason: u8 },
+    Publish(Message<'b>),
+    Ack { kind: Ack, packet_id: u16, reason: u8 },
+    /// With a reason code per filter.
+    Suback { packet_id: u16, reasons: &'b [u8] },
+    Unsuback,
+    Pingresp,
+    Disconnect { reason: u8 },
+}
+
+impl<'b> Packet<'b> {
+    /// Decodes a packet from its first byte and the `body` following the fixed header.
+    pub fn decode(first: u8, body: &'b [u8]) -> Result<Self, Malformed> {
+        let mut data = Decoder { data: body };
+        let packet = match first >> 4 {
+            2 => {
+                let flags = data.u8()?;
+                let reason = data.u8()?;
+                Packet::Connack {
+                    session_present: flags & 0x01 != 0,
+                    reason,
+                }
+            }
+            3 => {
+                let qos = Qos::from_bits((first >> 1) & 0x03).ok_or(Malformed)?;
+                let topic = data.string()?;
+                let packet_id = if qos == Qos::AtMostOnce { 0 } else { data.u16()? };
+                data.skip_properties()?;
+                Packet::Publish(Message {
+                    topic,
+                    payload: data.data,
+                    qos,
+                    dup: first & 0x08 != 0,
+                    packet_id,
+                })
+            }
+            kind @ 4..=7 => {
+                let kind = match kind {
+                    4 => Ack::Puback,
+                    5 => Ack::Pubrec,
+                    6 => Ack::Pubrel,
+                    _ => Ack::Pubcomp,
+                };
+                let packet_id = data.u16()?;
+                let reason = data.reason()?;
+                Packet::Ack {
+                    kind,
+                    packet_id,
+                    reason,
+                }
+            }
+            9 => {
+                let packet_id = data.u16()?;
+                data.skip_properties()?;
+                Packet::Suback {
+                    packet_id,
+                    reasons: data.data,
+                }
+            }
+            11 => Packet::Unsuback,
+            13 => Packet::Pingresp,
+            14 => Packet::Disconnect {
+                reason: data.reason()?,
+            },
+            _ => return Err(Malformed),
+        };
+        Ok(packet)
+    }
+}
@@ -0,0 +1,187 @@
+//! Commands invoked on `{prefix}/{chip_id}/cmd/<name>`, each answered with a JSON reply such as
+//! `{"command":"identify","correlation":"42","status":"ok"}`.
+//!
+//! The client skips MQTT v5 publish properties, so the response topic and correlation data are
+//! carried in the request payload instead, e.g.
+//! `{"response_topic":"embedded/reply/tool-1","correlation":"42"}`. The response topic must be under
+//! `{prefix}/reply/`, so a request can not make the device publish on another device's topics;
+//! without one, or with one outside of it, the reply goes to
+//! `{prefix}/{chip_id}/cmd/<name>/response`. An empty request payload is accepted.
+//! Replies are not retained and are published at QoS0, a tool getting none sends its request again.
+
+use core::fmt::Write;
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Command {
+    /// Restarts the device once the reply is sent.
+    Reboot,
+    /// Flashes every led, for `seconds` or 5 seconds.
+    Identify,
+    /// Reports the configuration.
+    Config,
+    /// Lists the visible Wi-Fi networks.
+    Scan,
+    /// Checks the clock, network and leds.
+    SelfTest,
+}
+
+impl Command {
+    pub const ALL: [Command; 5] = [
+        Command::Reboot,
+        Command::Identify,
+        Command::Config,
+        Command::Scan,
+        Command::SelfTest,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Reboot => "reboot",
+            Command::Identify => "identify",
+            Command::Config => "config",
+            Command::Scan => "scan",
+            Command::SelfTest => "self_test",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|command| command.name() == name)
+    }
+}
+
+pub const DEFAULT_IDENTIFY_SECONDS: u8 = 5;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Request<'a> {
+    #[serde(borrow)]
+    response_topic: Option<&'a str>,
+    #[serde(borrow)]
+    correlation: Option<&'a str>,
+    /// Duration of `identify`.
+    seconds: Option<u8>,
+}
+
+/// A topic under `{prefix}/reply/`, without wildcards.
+fn is_reply_topic(prefix: &str, topic: &str) -> bool {
+    let rest = topic
+        .strip_prefix(prefix)
+        .and_then(|rest| rest.strip_prefix("/reply/"));
+    match rest {
+        Some(rest) => !rest.is_empty() && !rest.contains(|c: char| matches!(c, '+' | '#')),
+        None => false,
+    }
+}
+
+/// A received command, copied out of the receive buffer so the client can be used to reply.
+pub struct Call {
+    pub name: String<16>,
+    /// `None` for unknown commands.
+    pub command: Option<Command>,
+    pub response_topic: String<64>,
+    pub correlation: Option<String<32>>,
+    pub seconds: Option<u8>,
+    /// The payload could not be understood, the call is only answered.
+    pub invalid: bool,
+}
+
+impl Call {
+    /// `None` when the name, correlation or response topic are too long to even reply.
+    pub fn new(prefix: &str, chip_id: &str, name: &str, payload: &[u8]) -> Option<Self> {
+        let (request, invalid) = if payload.iter().all(u8::is_ascii_whitespace) {
+            (Request::default(), false)
+        } else {
+            match serde_json_core::from_slice::<Request>(payload) {
+                Ok((request, _)) => (request, false),
+                Err(_) => (Request::default(), true),
+            }
+        };
+        let mut call = Call {
+            name: String::new(),
+            command: Command::from_name(name),
+            response_topic: String::new(),
+            correlation: None,
+            seconds: request.seconds,
+            invalid,
+        };
+        call.name.push_str(name).ok()?;
+        match request.response_topic {
+            Some(topic) if is_reply_topic(prefix, topic) => call.response_topic.push_str(topic).ok()?,
+            other => {
+                call.invalid |= other.is_some();
+                write!(call.response_topic, "{prefix}/{chip_id}/cmd/{name}/response").ok()?
+            }
+        }
+        if let Some(correlation) = request.correlation {
+            let mut owned = String::new();
+            owned.push_str(correlation).ok()?;
+            call.correlation = Some(owned);
+        }
+        Some(call)
+    }
+
+    /// Writes the reply to the call.
+    pub fn reply<T: Serialize>(
+        &self,
+        status: Status,
+        result: Option<T>,
+        buffer: &mut [u8],
+    ) -> Result<usize, serde_json_core::ser::Error> {
+        let response = Response {
+            command: &self.name,
+            correlation: self.correlation.as_deref(),
+            status,
+            result,
+        };
+        serde_json_core::to_slice(&response, buffer)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    Failed,
+    UnknownCommand,
+    InvalidRequest,
+}
+
+#[derive(Serialize)]
+struct Response<'a, T> {
+    command: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation: Option<&'a str>,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+}
+
+#[derive(Serialize)]
+pub struct ConfigReport<'a> {
+    pub version: &'a str,
+    pub chip_id: &'a str,
+    pub address: &'a str,
+    pub broker: &'a str,
+    pub leds: usize,
+    pub buttons: usize,
+    pub groups: &'a str,
+    pub uptime_s: u64,
+    pub time_source: Option<&'a str>,
+    pub power: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+pub struct ScanEntry<'a> {
+    pub ssid: &'a str,
+    pub rssi: i16,
+    pub channel: u8,
+}
+
+#[derive(Serialize)]
+pub struct SelfTestReport {
+    pub clock: bool,
+    pub network: bool,
+    pub outbox_free: usize,
+}
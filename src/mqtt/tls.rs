@@ -0,0 +1,187 @@
+//! TLS 1.3 transport to the broker, enabled with the `tls` feature.
+//!
+//! The broker certificate is pinned by its SHA-256 fingerprint, given at build time in
+//! `MQTT_TLS_FINGERPRINT` as printed by `openssl x509 -noout -fingerprint -sha256 -in cert.pem`.
+//! Only ECDSA P-256 server keys are supported, e.g. a self-signed Mosquitto certificate made with
+//! `openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes -subj /CN=<host> ...`.
+//!
+//! With the `tls-client-cert` feature the device also authenticates with a client certificate,
+//! read at build time from the DER files named by `MQTT_TLS_CLIENT_CERT` and `MQTT_TLS_CLIENT_KEY`
+//! (a PKCS#8 P-256 key, `openssl pkcs8 -topk8 -nocrypt -outform der`).
+
+use embedded_tls::handshake::certificate::{CertificateEntryRef, CertificateRef};
+use embedded_tls::handshake::certificate_verify::HandshakeVerifyRef;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CryptoProvider, SignatureScheme, TlsError, TlsVerifier,
+};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{DerSignature, Signature, VerifyingKey};
+#[cfg(feature = "tls-client-cert")]
+use p256::ecdsa::SigningKey;
+#[cfg(feature = "tls-client-cert")]
+use p256::pkcs8::DecodePrivateKey;
+use rand_chacha::ChaCha8Rng;
+use rand_core::{CryptoRngCore, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+
+/// Largest TLS record the broker may send, a full 16KiB record plus overhead.
+pub const TLS_READ_RECORD_SIZE: usize = 16640;
+pub const TLS_WRITE_RECORD_SIZE: usize = 4096;
+
+pub const SERVER_FINGERPRINT: [u8; 32] = parse_fingerprint(core::env!(
+    "MQTT_TLS_FINGERPRINT",
+    "No broker certificate fingerprint set"
+));
+
+#[cfg(feature = "tls-client-cert")]
+pub const CLIENT_CERT: &[u8] = include_bytes!(core::env!("MQTT_TLS_CLIENT_CERT"));
+#[cfg(feature = "tls-client-cert")]
+pub const CLIENT_KEY: &[u8] = include_bytes!(core::env!("MQTT_TLS_CLIENT_KEY"));
+
+/// Parses 32 hex encoded bytes, optionally separated by `:`, failing the build if malformed.
+const fn parse_fingerprint(text: &str) -> [u8; 32] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("invalid hex digit in MQTT_TLS_FINGERPRINT"),
+        }
+    }
+    let text = text.as_bytes();
+    let mut fingerprint = [0; 32];
+    let mut byte = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] == b':' {
+            i += 1;
+            continue;
+        }
+        if byte == 32 || i + 1 >= text.len() {
+            panic!("MQTT_TLS_FINGERPRINT must be a SHA-256 fingerprint");
+        }
+        fingerprint[byte] = nibble(text[i]) << 4 | nibble(text[i + 1]);
+        byte += 1;
+        i += 2;
+    }
+    if byte != 32 {
+        panic!("MQTT_TLS_FINGERPRINT must be a SHA-256 fingerprint");
+    }
+    fingerprint
+}
+
+/// `RoscRng` is not a `CryptoRng`, seed a ChaCha stream from it instead.
+pub fn tls_rng(seed_source: &mut impl RngCore) -> ChaCha8Rng {
+    let mut seed = [0; 32];
+    seed_source.fill_bytes(&mut seed);
+    ChaCha8Rng::from_seed(seed)
+}
+
+pub struct PinnedVerifier {
+    fingerprint: [u8; 32],
+    transcript: Option<Sha256>,
+    key: Option<VerifyingKey>,
+}
+
+/// DER encoded `id-ecPublicKey` followed by `prime256v1`.
+const EC_P256_OIDS: [u8; 21] = [
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce,
+    0x3d, 0x03, 0x01, 0x07, 0x03, 0x42,
+];
+
+/// Finds the uncompressed P-256 point of the certificate subject public key info.
+fn p256_public_key(der: &[u8]) -> Option<VerifyingKey> {
+    let start = der
+        .windows(EC_P256_OIDS.len())
+        .position(|window| window == EC_P256_OIDS)?
+        + EC_P256_OIDS.len();
+    // bit string padding byte, then the 65 bytes sec1 point
+    let point = der.get(start + 1..start + 66)?;
+    VerifyingKey::from_sec1_bytes(point).ok()
+}
+
+impl TlsVerifier<Aes128GcmSha256> for PinnedVerifier {
+    fn set_hostname_verification(&mut self, _hostname: &str) -> Result<(), TlsError> {
+        // the pinned certificate identifies the broker, whatever its name
+        Ok(())
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &Sha256,
+        _ca: &Option<Certificate>,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        let der = match cert.entries.first() {
+            Some(CertificateEntryRef::X509(der)) => *der,
+            _ => return Err(TlsError::InvalidCertificate),
+        };
+        if Sha256::digest(der)[..] != self.fingerprint[..] {
+            defmt::error!("broker certificate does not match the pinned fingerprint");
+            return Err(TlsError::InvalidCertificate);
+        }
+        self.key = Some(p256_public_key(der).ok_or(TlsError::InvalidCertificate)?);
+        self.transcript = Some(transcript.clone());
+        Ok(())
+    }
+
+    fn verify_signature(&mut self, verify: HandshakeVerifyRef) -> Result<(), TlsError> {
+        if verify.signature_scheme != SignatureScheme::EcdsaSecp256r1Sha256 {
+            return Err(TlsError::InvalidSignatureScheme);
+        }
+        let key = self.key.as_ref().ok_or(TlsError::InvalidCertificate)?;
+        let transcript = self
+            .transcript
+            .take()
+            .ok_or(TlsError::InvalidCertificate)?;
+        // RFC 8446 4.4.3
+        let mut message = [0x20; 64 + 33 + 1 + 32];
+        message[64..97].copy_from_slice(b"TLS 1.3, server CertificateVerify");
+        message[97] = 0;
+        message[98..].copy_from_slice(&transcript.finalize());
+        let signature = Signature::from_der(verify.signature).map_err(|_| TlsError::InvalidSignature)?;
+        key.verify(&message, &signature)
+            .map_err(|_| TlsError::InvalidSignature)
+    }
+}
+
+pub struct PinnedProvider<R> {
+    rng: R,
+    verifier: PinnedVerifier,
+}
+
+impl<R: CryptoRngCore> PinnedProvider<R> {
+    pub fn new(rng: R, fingerprint: [u8; 32]) -> Self {
+        Self {
+            rng,
+            verifier: PinnedVerifier {
+                fingerprint,
+                transcript: None,
+                key: None,
+            },
+        }
+    }
+}
+
+impl<R: CryptoRngCore> CryptoProvider for PinnedProvider<R> {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = DerSignature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Aes128GcmSha256>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    #[cfg(feature = "tls-client-cert")]
+    fn signer(
+        &mut self,
+        key_der: &[u8],
+    ) -> Result<(impl p256::ecdsa::signature::SignerMut<DerSignature>, SignatureScheme), TlsError>
+    {
+        let key = SigningKey::from_pkcs8_der(key_der).map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((key, SignatureScheme::EcdsaSecp256r1Sha256))
+    }
+}
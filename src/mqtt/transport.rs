@@ -0,0 +1,46 @@
+//! Broker connection wrapper telling the session whether a receive already read part of a packet.
+//!
+//! The client reads a packet over several reads, so a receive dropped after its first read would
+//! leave the rest of the packet to be parsed as a new one. The embassy-net and embedded-tls reads
+//! themselves lose nothing when dropped, so a receive which did not read anything yet can be
+//! raced against other events, and one which did must be finished.
+
+use core::cell::Cell;
+
+use embedded_io_async::{ErrorType, Read, Write};
+
+pub struct TrackedConnection<'a, T> {
+    inner: T,
+    read: &'a Cell<bool>,
+}
+
+impl<'a, T> TrackedConnection<'a, T> {
+    /// Sets `read` whenever bytes are read from `inner`.
+    pub fn new(inner: T, read: &'a Cell<bool>) -> Self {
+        Self { inner, read }
+    }
+}
+
+impl<T: ErrorType> ErrorType for TrackedConnection<'_, T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for TrackedConnection<'_, T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.inner.read(buf).await?;
+        if len > 0 {
+            self.read.set(true);
+        }
+        Ok(len)
+    }
+}
+
+impl<T: Write> Write for TrackedConnection<'_, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.inner.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
@@ -0,0 +1,35 @@
+use defmt::*;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::{IpAddress, IpEndpoint, Stack};
+
+use super::mdns;
+use crate::config::BrokerConfig;
+
+pub const MDNS_SERVICE: &str = "_mqtt._tcp.local";
+
+/// Resolves the broker through mDNS service browsing when no host is configured,
+/// mDNS for `.local` hosts and DNS otherwise, falling back to the configured address.
+pub async fn resolve(stack: Stack<'_>, config: &BrokerConfig, default_port: u16) -> IpEndpoint {
+    let port = config.port.unwrap_or(default_port);
+    let host = config.host.as_str();
+    if host.is_empty() {
+        if let Some(resolved) = mdns::query(stack, MDNS_SERVICE, mdns::TYPE_PTR).await {
+            info!("broker found over mdns : {}", resolved);
+            return IpEndpoint::new(resolved.address.into(), resolved.port.unwrap_or(port));
+        }
+        warn!("no broker advertised over mdns");
+    } else if host.ends_with(".local") {
+        if let Some(resolved) = mdns::query(stack, host, mdns::TYPE_A).await {
+            return IpEndpoint::new(resolved.address.into(), port);
+        }
+        warn!("could not resolve {} over mdns", host);
+    } else {
+        match stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => return IpEndpoint::new(addresses[0], port),
+            Ok(_) => warn!("no address for {}", host),
+            Err(e) => warn!("could not resolve {} : {:?}", host, e),
+        }
+    }
+    info!("using fallback broker address {}", config.fallback);
+    IpEndpoint::new(IpAddress::Ipv4(config.fallback), port)
+}
@@ -0,0 +1,160 @@
+//! Minimal DHCP server (RFC 2131) for the provisioning access point, leasing one address per
+//! client from a small pool, with the device as router and DNS server.
+
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Ipv4Address, Stack};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+const LEASE_SECONDS: u32 = 3600;
+const POOL_START: u8 = 10;
+const POOL_SIZE: usize = 8;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+/// Hardware addresses, the index being the offset of the address leased in the pool.
+struct Leases {
+    macs: [Option<[u8; 6]>; POOL_SIZE],
+    /// Slot given away next once the pool is exhausted. Leases are never released, so the pool
+    /// fills in order and this is always the oldest one.
+    oldest: usize,
+}
+
+impl Leases {
+    const fn new() -> Self {
+        Self {
+            macs: [None; POOL_SIZE],
+            oldest: 0,
+        }
+    }
+
+    /// Index of the lease of `mac`, reusing the oldest one when the pool is exhausted, the
+    /// other clients keeping their address.
+    fn lease(&mut self, mac: [u8; 6]) -> usize {
+        if let Some(index) = self.macs.iter().position(|lease| *lease == Some(mac)) {
+            return index;
+        }
+        let index = match self.macs.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                let index = self.oldest;
+                self.oldest = (index + 1) % POOL_SIZE;
+                index
+            }
+        };
+        self.macs[index] = Some(mac);
+        index
+    }
+}
+
+/// Value of the DHCP message type option.
+fn message_type(options: &[u8]) -> Option<u8> {
+    let mut options = options;
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            0 => options = rest,
+            OPTION_END => return None,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let value = rest.get(..len as usize)?;
+                if code == OPTION_MESSAGE_TYPE {
+                    return value.first().copied();
+                }
+                options = &rest[len as usize..];
+            }
+        }
+    }
+    None
+}
+
+/// Serves addresses in the /24 of `server` on the interface of `stack`.
+pub async fn run(stack: Stack<'_>, server: Ipv4Address) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 600];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 600];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    unwrap!(socket.bind(SERVER_PORT));
+
+    let mut leases = Leases::new();
+    let mut packet = [0; 576];
+    loop {
+        let len = match socket.recv_from(&mut packet).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                warn!("dhcp receive failed : {:?}", e);
+                continue;
+            }
+        };
+        // a BOOTREQUEST over ethernet
+        if len < OPTIONS_OFFSET || packet[0] != 1 || packet[1] != 1 || packet[2] != 6 {
+            continue;
+        }
+        if packet[236..240] != MAGIC_COOKIE {
+            continue;
+        }
+        let reply_type = match message_type(&packet[OPTIONS_OFFSET..len]) {
+            Some(DHCP_DISCOVER) => DHCP_OFFER,
+            Some(DHCP_REQUEST) => DHCP_ACK,
+            _ => continue,
+        };
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&packet[28..34]);
+        let [a, b, c, _] = server.octets();
+        let index = leases.lease(mac);
+        let client = [a, b, c, POOL_START + index as u8];
+        debug!("dhcp {} for {:02x} : {}", reply_type, mac, client);
+
+        // the request header is kept : xid, flags and chaddr are echoed back
+        packet[0] = 2;
+        packet[3] = 0;
+        packet[8..12].fill(0);
+        packet[12..16].fill(0);
+        packet[16..20].copy_from_slice(&client);
+        packet[20..24].copy_from_slice(&server.octets());
+        packet[24..28].fill(0);
+        packet[44..236].fill(0);
+        let mut options = OPTIONS_OFFSET;
+        let mut option = |code: u8, value: &[u8]| {
+            packet[options] = code;
+            packet[options + 1] = value.len() as u8;
+            packet[options + 2..options + 2 + value.len()].copy_from_slice(value);
+            options += 2 + value.len();
+        };
+        option(OPTION_MESSAGE_TYPE, &[reply_type]);
+        option(OPTION_SERVER_ID, &server.octets());
+        option(OPTION_LEASE_TIME, &LEASE_SECONDS.to_be_bytes());
+        option(OPTION_SUBNET_MASK, &[255, 255, 255, 0]);
+        option(OPTION_ROUTER, &server.octets());
+        option(OPTION_DNS, &server.octets());
+        packet[options] = OPTION_END;
+        let reply_len = (options + 1).max(300);
+        packet[options + 1..reply_len].fill(0);
+
+        // the client has no address yet
+        let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT);
+        if let Err(e) = socket.send_to(&packet[..reply_len], broadcast).await {
+            warn!("dhcp reply failed : {:?}", e);
+        }
+    }
+}
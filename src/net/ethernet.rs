@@ -0,0 +1,72 @@
+//! Wired networking through a WIZnet W5500 module, for installations where Wi-Fi is
+//! unreliable. Enabled by the `ethernet-w5500` feature, the module replaces the cyw43 radio,
+//! so there is no provisioning access point, bluetooth nor scan.
+//!
+//! The module is wired to SPI0 away from the led and button pins : SCK on GP2, MOSI on GP3,
+//! MISO on GP4, CS on GP5, INT on GP6 and RST on GP7.
+
+use embassy_net_wiznet::chip::W5500;
+use embassy_net_wiznet::State;
+use embassy_rp::gpio::{Input, Level, Output, Pull};
+use embassy_rp::peripherals::{DMA_CH1, DMA_CH2, PIN_2, PIN_3, PIN_4, PIN_5, PIN_6, PIN_7, SPI0};
+use embassy_rp::spi::{Async, Config, Spi};
+use embassy_time::Delay;
+use embedded_hal_bus::spi::ExclusiveDevice;
+use static_cell::StaticCell;
+
+/// Fastest clock the W5500 accepts.
+const SPI_FREQUENCY: u32 = 50_000_000;
+
+pub type EthernetSpi = ExclusiveDevice<Spi<'static, SPI0, Async>, Output<'static>, Delay>;
+pub type EthernetDevice = embassy_net_wiznet::Device<'static>;
+/// Drives the module, to be run in its own task.
+pub type EthernetRunner =
+    embassy_net_wiznet::Runner<'static, W5500, EthernetSpi, Input<'static>, Output<'static>>;
+
+/// Peripherals used by the module.
+pub struct EthernetPins {
+    pub spi: SPI0,
+    pub sck: PIN_2,
+    pub mosi: PIN_3,
+    pub miso: PIN_4,
+    pub cs: PIN_5,
+    pub int: PIN_6,
+    pub reset: PIN_7,
+    pub tx_dma: DMA_CH1,
+    pub rx_dma: DMA_CH2,
+}
+
+/// Locally administered unicast address derived from the chip id, stable across reboots.
+pub fn mac_address(chip_id: u32) -> [u8; 6] {
+    let [a, b, c, d] = chip_id.to_be_bytes();
+    [0x02, 0x00, a, b, c, d]
+}
+
+/// Resets and configures the module, returning the network driver for `embassy_net::new`.
+pub async fn new(pins: EthernetPins, mac_address: [u8; 6]) -> (EthernetDevice, EthernetRunner) {
+    let mut config = Config::default();
+    config.frequency = SPI_FREQUENCY;
+    let spi = Spi::new(
+        pins.spi,
+        pins.sck,
+        pins.mosi,
+        pins.miso,
+        pins.tx_dma,
+        pins.rx_dma,
+        config,
+    );
+    let cs = Output::new(pins.cs, Level::High);
+    let int = Input::new(pins.int, Pull::Up);
+    let reset = Output::new(pins.reset, Level::High);
+
+    static STATE: StaticCell<State<8, 8>> = StaticCell::new();
+    let state = STATE.init(State::new());
+    embassy_net_wiznet::new(
+        mac_address,
+        state,
+        ExclusiveDevice::new(spi, cs, Delay),
+        int,
+        reset,
+    )
+    .await
+}
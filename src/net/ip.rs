@@ -0,0 +1,81 @@
+//! Addressing of the station interface, as set in [`IpSettings`].
+//!
+//! With DHCP and a timeout, [`run`] switches to the fallback address when no lease comes in
+//! time, and back to DHCP when the link drops. Without a fallback the failure is logged and
+//! [`ADDRESS_MISSING`] is set, making the onboard led blink, while DHCP keeps trying.
+//!
+//! embassy-net has no router discovery, so the automatic IPv6 address is the link-local one
+//! derived from the hardware address, without a global prefix.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::*;
+use embassy_net::{ConfigV4, ConfigV6, HardwareAddress, Ipv6Address, Ipv6Cidr, Stack, StaticConfigV6};
+use embassy_time::with_timeout;
+
+use crate::config::{IpSettings, Ipv4Mode, Ipv6Mode};
+use crate::{log_error, log_warn};
+
+/// Set while the DHCP timeout expired with no fallback address.
+pub static ADDRESS_MISSING: AtomicBool = AtomicBool::new(false);
+
+/// `fe80::/64` with the modified EUI-64 interface identifier of `mac`.
+pub fn link_local(mac: [u8; 6]) -> StaticConfigV6 {
+    let [a, b, c, d, e, f] = mac;
+    let mut octets = [0; 16];
+    octets[..2].copy_from_slice(&[0xfe, 0x80]);
+    octets[8..].copy_from_slice(&[a ^ 0x02, b, c, 0xff, 0xfe, d, e, f]);
+    StaticConfigV6 {
+        address: Ipv6Cidr::new(Ipv6Address::from(octets), 64),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    }
+}
+
+/// Applies `settings` to `stack` for as long as the device runs.
+pub async fn run(stack: Stack<'_>, settings: &IpSettings) -> ! {
+    if settings.ipv6 == Ipv6Mode::LinkLocal {
+        match stack.hardware_address() {
+            HardwareAddress::Ethernet(mac) => {
+                let config = link_local(mac.0);
+                info!("ipv6 address : {}", config.address);
+                stack.set_config_v6(ConfigV6::Static(config));
+            }
+            #[allow(unreachable_patterns)]
+            _ => warn!("no link-local ipv6 address without an ethernet address"),
+        }
+    }
+    let dhcp_timeout = match (&settings.ipv4, settings.dhcp_timeout) {
+        (Ipv4Mode::Dhcp, Some(timeout)) => timeout,
+        // nothing to watch, the stack handles the rest
+        _ => core::future::pending().await,
+    };
+    loop {
+        stack.wait_link_up().await;
+        if with_timeout(dhcp_timeout, stack.wait_config_up()).await.is_err() {
+            match &settings.fallback {
+                Some(fallback) => {
+                    log_warn!(
+                        "no dhcp lease after {} s, using the fallback address",
+                        dhcp_timeout.as_secs()
+                    );
+                    stack.set_config_v4(ConfigV4::Static(fallback.clone()));
+                }
+                None => {
+                    log_error!("no dhcp lease after {} s", dhcp_timeout.as_secs());
+                    ADDRESS_MISSING.store(true, Ordering::Relaxed);
+                    stack.wait_config_up().await;
+                }
+            }
+        }
+        ADDRESS_MISSING.store(false, Ordering::Relaxed);
+        if let Some(config) = stack.config_v4() {
+            info!("ipv4 address : {}", config.address);
+        }
+        stack.wait_link_down().await;
+        if settings.fallback.is_some() {
+            // another network may have a dhcp server
+            stack.set_config_v4(ConfigV4::Dhcp(Default::default()));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! One-shot multicast DNS queries (RFC 6762).
+//!
+//! Queries are sent from a port other than 5353, so responders answer with a unicast
+//! response to that port and no multicast group needs to be joined.
+
+use defmt::*;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+use heapless::String;
+
+const MDNS_ENDPOINT: IpEndpoint = IpEndpoint::new(IpAddress::v4(224, 0, 0, 251), 5353);
+const QUERY_PORT: u16 = 49353;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_SRV: u16 = 33;
+
+type Name = String<64>;
+
+/// Address, and port when the response carried a SRV record.
+#[derive(Clone, Copy, Format)]
+pub struct Resolved {
+    pub address: Ipv4Address,
+    pub port: Option<u16>,
+}
+
+fn write_query(buffer: &mut [u8], name: &str, record_type: u16) -> Option<usize> {
+    // id 0, standard query, one question
+    let header = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    buffer.get_mut(..12)?.copy_from_slice(&header);
+    let mut len = 12;
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        if label.len() > 63 {
+            return None;
+        }
+        *buffer.get_mut(len)? = label.len() as u8;
+        buffer
+            .get_mut(len + 1..len + 1 + label.len())?
+            .copy_from_slice(label.as_bytes());
+        len += 1 + label.len();
+    }
+    *buffer.get_mut(len)? = 0;
+    len += 1;
+    // class IN with the unicast response bit
+    let question = [
+        (record_type >> 8) as u8,
+        record_type as u8,
+        0x80,
+        0x01,
+    ];
+    buffer.get_mut(len..len + 4)?.copy_from_slice(&question);
+    Some(len + 4)
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *message.get(offset)?,
+        *message.get(offset + 1)?,
+    ]))
+}
+
+/// Reads a possibly compressed name at `offset`, returning the offset following it.
+fn read_name(message: &[u8], mut offset: usize, name: &mut Name) -> Option<usize> {
+    let mut end = None;
+    // bounds the number of compression pointers followed
+    for _ in 0..32 {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => return Some(end.unwrap_or(offset + 1)),
+            l if l & 0xc0 == 0xc0 => {
+                end.get_or_insert(offset + 2);
+                offset = (read_u16(message, offset)? & 0x3fff) as usize;
+            }
+            l => {
+                let label = core::str::from_utf8(message.get(offset + 1..offset + 1 + l)?).ok()?;
+                if !name.is_empty() {
+                    name.push('.').ok()?;
+                }
+                name.push_str(label).ok()?;
+                offset += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+fn skip_name(message: &[u8], offset: usize) -> Option<usize> {
+    read_name(message, offset, &mut Name::new())
+}
+
+/// Address of `name` for an A query, or of the instance a PTR answer for `name` points to, with
+/// the port of its SRV record. Records about other names, e.g. unrelated announcements, are ignored.
+fn parse_response(message: &[u8], name: &str, query_type: u16) -> Option<Resolved> {
+    let name = name.trim_end_matches('.');
+    let questions = read_u16(message, 4)?;
+    let records = read_u16(message, 6)? as usize
+        + read_u16(message, 8)? as usize
+        + read_u16(message, 10)? as usize;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(message, offset)? + 4;
+    }
+    let mut instance: Option<Name> = None;
+    let mut services: heapless::Vec<(Name, Name, u16), 4> = heapless::Vec::new();
+    let mut addresses: heapless::Vec<(Name, Ipv4Address), 4> = heapless::Vec::new();
+    for _ in 0..records {
+        let mut owner = Name::new();
+        offset = read_name(message, offset, &mut owner)?;
+        let record_type = read_u16(message, offset)?;
+        let data_len = read_u16(message, offset + 8)? as usize;
+        let data = offset + 10;
+        offset = data + data_len;
+        match record_type {
+            TYPE_A if data_len == 4 => {
+                let octets = message.get(data..data + 4)?;
+                let address = Ipv4Address::new(octets[0], octets[1], octets[2], octets[3]);
+                addresses.push((owner, address)).ok();
+            }
+            TYPE_PTR if instance.is_none() && owner.eq_ignore_ascii_case(name) => {
+                let mut target = Name::new();
+                read_name(message, data, &mut target)?;
+                instance = Some(target);
+            }
+            TYPE_SRV => {
+                let port = read_u16(message, data + 4)?;
+                let mut target = Name::new();
+                read_name(message, data + 6, &mut target)?;
+                services.push((owner, target, port)).ok();
+            }
+            _ => {}
+        }
+    }
+    let (host, port) = if query_type == TYPE_PTR {
+        let instance = instance?;
+        let (_, target, port) = services
+            .iter()
+            .find(|(owner, _, _)| owner.eq_ignore_ascii_case(&instance))?;
+        (target.as_str(), Some(*port))
+    } else {
+        (name, None)
+    };
+    let address = addresses
+        .iter()
+        .find(|(owner, _)| owner.eq_ignore_ascii_case(host))
+        .map(|(_, address)| *address)?;
+    Some(Resolved { address, port })
+}
+
+/// Queries `name`, e.g. `_mqtt._tcp.local` with [`TYPE_PTR`] or `broker.local` with [`TYPE_A`].
+pub async fn query(stack: Stack<'_>, name: &str, record_type: u16) -> Option<Resolved> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(QUERY_PORT) {
+        warn!("mdns bind failed : {:?}", e);
+        return None;
+    }
+
+    let mut message = [0; 512];
+    let len = write_query(&mut message, name, record_type)?;
+    if let Err(e) = socket.send_to(&message[..len], MDNS_ENDPOINT).await {
+        warn!("mdns query failed : {:?}", e);
+        return None;
+    }
+    loop {
+        let (len, _) = match with_timeout(TIMEOUT, socket.recv_from(&mut message)).await {
+            Ok(Ok(received)) => received,
+            Ok(Err(e)) => {
+                warn!("mdns receive failed : {:?}", e);
+                continue;
+            }
+            Err(_) => return None,
+        };
+        // ignore other queries, truncated headers and empty answers
+        if len < 12 || message[2] & 0x80 == 0 {
+            continue;
+        }
+        if let Some(resolved) = parse_response(&message[..len], name, record_type) {
+            return Some(resolved);
+        }
+    }
+}
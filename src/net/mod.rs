@@ -0,0 +1,10 @@
+pub mod broker;
+pub mod dhcp_server;
+#[cfg(feature = "ethernet-w5500")]
+pub mod ethernet;
+pub mod ip;
+pub mod mdns;
+pub mod power;
+pub mod provisioning;
+pub mod sntp;
+pub mod wifi;
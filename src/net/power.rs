@@ -0,0 +1,121 @@
+//! Wi-Fi power management profile, applied by [`wifi::run`](super::wifi::run).
+//!
+//! The profile defaults to `WIFI_POWER` at build time and is changed over MQTT on `power`.
+//! Whatever the profile, [`record_activity`] switches the radio to performance until
+//! [`BURST_HOLD`] after the last activity, so that replies to a button press or a command are
+//! not held back by power save.
+
+use core::cell::Cell;
+
+use cyw43::PowerManagementMode;
+use defmt::Format;
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+
+/// How long the radio stays in performance after the last activity.
+pub const BURST_HOLD: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum PowerProfile {
+    /// Lowest latency, the radio barely sleeps.
+    Performance,
+    /// The cyw43 default, sleeping between beacons.
+    Balanced,
+    /// Lowest consumption, replies may be delayed by several beacon intervals.
+    Aggressive,
+}
+
+impl PowerProfile {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Performance => "performance",
+            Self::Balanced => "balanced",
+            Self::Aggressive => "aggressive",
+        }
+    }
+
+    pub fn parse(name: &[u8]) -> Option<Self> {
+        match name {
+            b"performance" => Some(Self::Performance),
+            b"balanced" => Some(Self::Balanced),
+            b"aggressive" => Some(Self::Aggressive),
+            _ => None,
+        }
+    }
+
+    pub fn mode(self) -> PowerManagementMode {
+        match self {
+            Self::Performance => PowerManagementMode::Performance,
+            Self::Balanced => PowerManagementMode::PowerSave,
+            Self::Aggressive => PowerManagementMode::Aggressive,
+        }
+    }
+
+    /// From `WIFI_POWER` at build time, balanced if unset.
+    pub fn from_env() -> Self {
+        option_env!("WIFI_POWER")
+            .map(|name| Self::parse(name.as_bytes()).expect("invalid WIFI_POWER"))
+            .unwrap_or(Self::Balanced)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PowerState {
+    /// `None` until set over MQTT, for the build time default.
+    profile: Option<PowerProfile>,
+    last_activity: Option<Instant>,
+}
+
+static STATE: Mutex<ThreadModeRawMutex, Cell<PowerState>> = Mutex::new(Cell::new(PowerState {
+    profile: None,
+    last_activity: None,
+}));
+
+/// Signaled when the profile wanted may have changed.
+pub static CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Profile outside of activity bursts.
+pub fn profile() -> PowerProfile {
+    STATE
+        .lock(|state| state.get().profile)
+        .unwrap_or_else(PowerProfile::from_env)
+}
+
+/// Profile the radio should be in now.
+pub fn wanted() -> PowerProfile {
+    let busy = STATE
+        .lock(|state| state.get().last_activity)
+        .is_some_and(|at| Instant::now() < at + BURST_HOLD);
+    if busy {
+        PowerProfile::Performance
+    } else {
+        profile()
+    }
+}
+
+/// Starts or extends an activity burst.
+pub fn record_activity() {
+    STATE.lock(|state| {
+        let mut current = state.get();
+        current.last_activity = Some(Instant::now());
+        state.set(current);
+    });
+    CHANGED.signal(());
+}
+
+/// Handles a `power` payload.
+pub fn on_profile_message(payload: &[u8]) -> bool {
+    let profile = match PowerProfile::parse(payload) {
+        Some(profile) => profile,
+        None => return false,
+    };
+    defmt::info!("wifi power profile : {}", profile);
+    STATE.lock(|state| {
+        let mut current = state.get();
+        current.profile = Some(profile);
+        state.set(current);
+    });
+    CHANGED.signal(());
+    true
+}
@@ -0,0 +1,336 @@
+//! Provisioning mode : an open access point named `mqtt-pico-<chip id>` with a DHCP server,
+//! a DNS server answering every name with the device address, and a web page to set the
+//! Wi-Fi network, broker, login and topic prefix. Saving stores them and reboots.
+
+use core::fmt::Write as _;
+
+use cyw43::Control;
+use defmt::*;
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Ipv4Cidr, Stack};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+
+use super::dhcp_server;
+use crate::config::{is_topic_level, KnownNetworks, MqttSettings, Secret, WifiNetwork};
+use crate::storage::SharedStorage;
+
+pub const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+pub const CHANNEL: u8 = 6;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Static configuration of the stack while provisioning.
+pub fn stack_config() -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
+        address: Ipv4Cidr::new(ADDRESS, 24),
+        gateway: None,
+        dns_servers: heapless::Vec::new(),
+    })
+}
+
+/// Answers every A query with the device address, so any page opens the form.
+async fn dns_server(stack: Stack<'_>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 512];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; 512];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    unwrap!(socket.bind(53));
+    let mut packet = [0; 512];
+    loop {
+        let (len, from) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("dns receive failed : {:?}", e);
+                continue;
+            }
+        };
+        // a single question, name then type and class
+        if len < 12 || packet[2] & 0x80 != 0 || packet[4..6] != [0, 1] {
+            continue;
+        }
+        let name_end = match packet[12..len].iter().position(|&b| b == 0) {
+            Some(end) => 12 + end + 1,
+            None => continue,
+        };
+        let question_end = name_end + 4;
+        if question_end > len {
+            continue;
+        }
+        let is_a = packet[name_end..name_end + 4] == [0, 1, 0, 1];
+        // no room after a long question for the answer and its address
+        if is_a && question_end + 16 > packet.len() {
+            continue;
+        }
+        // response, recursion available, no other sections
+        packet[2] = 0x84 | (packet[2] & 0x01);
+        packet[3] = 0x80;
+        packet[6..12].copy_from_slice(&[0, is_a as u8, 0, 0, 0, 0]);
+        let mut reply_len = question_end;
+        if is_a {
+            let answer = [
+                // pointer to the name in the question, type A, class IN, ttl 60 s, 4 bytes
+                0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4,
+            ];
+            packet[reply_len..reply_len + answer.len()].copy_from_slice(&answer);
+            reply_len += answer.len();
+            packet[reply_len..reply_len + 4].copy_from_slice(&ADDRESS.octets());
+            reply_len += 4;
+        }
+        if let Err(e) = socket.send_to(&packet[..reply_len], from).await {
+            warn!("dns reply failed : {:?}", e);
+        }
+    }
+}
+
+/// Decodes an `application/x-www-form-urlencoded` value.
+fn url_decode<const N: usize>(value: &str) -> Option<String<N>> {
+    let mut decoded = heapless::Vec::<u8, N>::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                (high * 16 + low) as u8
+            }
+            byte => byte,
+        };
+        decoded.push(byte).ok()?;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Value of `name` in a form body.
+fn form_field<const N: usize>(body: &str, name: &str) -> Option<String<N>> {
+    body.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| url_decode(value))
+}
+
+/// Stores the submitted form, returning false if it is incomplete or invalid.
+async fn save(storage: &SharedStorage, body: &str) -> bool {
+    let ssid: String<32> = form_field(body, "ssid").unwrap_or_default();
+    let password: String<64> = form_field(body, "password").unwrap_or_default();
+    let network = match WifiNetwork::new(&ssid, &password, u8::MAX) {
+        Ok(network) => network,
+        Err(e) => {
+            warn!("invalid network : {}", e);
+            return false;
+        }
+    };
+    let mut settings = MqttSettings::load(storage).await;
+    match form_field(body, "host") {
+        Some(host) => settings.broker.host = host,
+        None => return false,
+    }
+    settings.broker.port = match form_field::<5>(body, "port").as_deref() {
+        None | Some("") => None,
+        Some(port) => match port.parse() {
+            Ok(port) => Some(port),
+            Err(_) => return false,
+        },
+    };
+    match form_field(body, "username") {
+        Some(username) => settings.credentials.username = username,
+        None => return false,
+    }
+    // left empty to keep the current one
+    if let Some(password) = form_field::<64>(body, "mqtt_password").filter(|p| !p.is_empty()) {
+        settings.credentials.password = unwrap!(Secret::new(&password));
+    }
+    match form_field::<24>(body, "prefix") {
+        Some(prefix) if is_topic_level(&prefix) => {
+            settings.prefix = prefix
+        }
+        _ => return false,
+    }
+
+    let mut networks = KnownNetworks::load(storage).await;
+    networks.remember(network);
+    if let Err(e) = networks.save(storage).await {
+        error!("could not save networks : {}", e);
+        return false;
+    }
+    if let Err(e) = settings.save(storage).await {
+        error!("could not save mqtt settings : {}", e);
+        return false;
+    }
+    info!("provisioned for {}", ssid);
+    true
+}
+
+async fn respond(socket: &mut TcpSocket<'_>, status: &str, body: &str) -> Result<(), embassy_net::tcp::Error> {
+    let mut head: String<128> = String::new();
+    let _ = write!(
+        head,
+        "HTTP/1.1 {status}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.flush().await
+}
+
+/// The form, pre-filled with the current non secret settings.
+fn form(settings: &MqttSettings, message: &str) -> String<1536> {
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>mqtt_pico setup</title></head><body><h1>mqtt_pico setup</h1><p>{message}</p>\
+         <form method=\"post\" action=\"/\">\
+         <p>Wi-Fi network<br><input name=\"ssid\" maxlength=\"32\" required></p>\
+         <p>Wi-Fi password<br><input name=\"password\" type=\"password\" maxlength=\"64\"></p>\
+         <p>Broker host, empty to search over mDNS<br><input name=\"host\" maxlength=\"64\" value=\"{}\"></p>\
+         <p>Broker port, empty for the default<br><input name=\"port\" type=\"number\" value=\"{}\"></p>\
+         <p>Broker user<br><input name=\"username\" maxlength=\"32\" value=\"{}\"></p>\
+         <p>Broker password, empty to keep<br><input name=\"mqtt_password\" type=\"password\" maxlength=\"64\"></p>\
+         <p>Topic prefix<br><input name=\"prefix\" maxlength=\"24\" required value=\"{}\"></p>\
+         <p><button>Save and restart</button></p></form></body></html>",
+        Escaped(&settings.broker.host),
+        settings.broker.port.map(port_text).unwrap_or_default(),
+        Escaped(&settings.credentials.username),
+        Escaped(&settings.prefix),
+    );
+    page
+}
+
+/// Text escaped for an HTML attribute value or element content.
+struct Escaped<'a>(&'a str);
+
+impl core::fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '&' => f.write_str("&amp;")?,
+                '<' => f.write_str("&lt;")?,
+                '>' => f.write_str("&gt;")?,
+                '"' => f.write_str("&quot;")?,
+                '\'' => f.write_str("&#39;")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn port_text(port: u16) -> String<5> {
+    let mut text = String::new();
+    let _ = write!(text, "{port}");
+    text
+}
+
+/// Serves the form until valid settings are submitted.
+async fn http_server(stack: Stack<'_>, storage: &SharedStorage) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut request = [0; 1024];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(HTTP_TIMEOUT));
+        if let Err(e) = socket.accept(80).await {
+            warn!("http accept failed : {:?}", e);
+            continue;
+        }
+        // the whole request, up to the size announced in its headers
+        let mut len = 0;
+        let complete = loop {
+            // the socket timeout ends stalled requests
+            match socket.read(&mut request[len..]).await {
+                Ok(0) | Err(_) => break None,
+                Ok(read) => len += read,
+            }
+            if let Some(head_len) = request[..len].windows(4).position(|end| end == b"\r\n\r\n") {
+                let head = match core::str::from_utf8(&request[..head_len]) {
+                    Ok(head) => head,
+                    Err(_) => break None,
+                };
+                let content_length = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if len - head_len - 4 >= content_length {
+                    break Some((head_len, content_length));
+                }
+            }
+            if len == request.len() {
+                break None;
+            }
+        };
+        // the body is checked on its own, its announced length may split a character
+        let parsed = complete.and_then(|(head_len, body_len)| {
+            let head = core::str::from_utf8(&request[..head_len]).ok()?;
+            let body = core::str::from_utf8(&request[head_len + 4..head_len + 4 + body_len]).ok()?;
+            Some((head, body))
+        });
+        let (head, body) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                socket.abort();
+                continue;
+            }
+        };
+        let settings = MqttSettings::load(storage).await;
+        let result = if head.starts_with("POST ") {
+            if save(storage, body).await {
+                if let Err(e) = respond(&mut socket, "200 OK", "<p>Saved, restarting.</p>").await {
+                    warn!("http response failed : {:?}", e);
+                }
+                socket.close();
+                // let the page reach the browser
+                Timer::after_secs(1).await;
+                return;
+            }
+            respond(&mut socket, "400 Bad Request", &form(&settings, "Invalid settings, try again.")).await
+        } else {
+            // any other page, so phones detect the captive portal
+            respond(&mut socket, "200 OK", &form(&settings, "")).await
+        };
+        if let Err(e) = result {
+            warn!("http response failed : {:?}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+fn ap_name(chip_id: &str) -> String<32> {
+    let mut name = String::new();
+    let _ = write!(name, "mqtt-pico-{}", chip_id.trim_start_matches("ksl-"));
+    name
+}
+
+/// Runs the access point until settings are saved, then reboots.
+pub async fn run(control: &mut Control<'_>, stack: Stack<'_>, storage: &SharedStorage, chip_id: &str) -> ! {
+    let name = ap_name(chip_id);
+    info!("provisioning on access point {}", name);
+    control.start_ap_open(&name, CHANNEL).await;
+    control.gpio_set(0, true).await;
+    stack.wait_config_up().await;
+    match select3(
+        dhcp_server::run(stack, ADDRESS),
+        dns_server(stack),
+        http_server(stack, storage),
+    )
+    .await
+    {
+        Either3::First(never) | Either3::Second(never) => match never {},
+        Either3::Third(()) => {}
+    }
+    info!("provisioned, rebooting");
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,94 @@
+//! Minimal SNTP client (RFC 4330).
+
+use defmt::*;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::clock::{self, TimeSource};
+
+const NTP_PORT: u16 = 123;
+const LOCAL_PORT: u16 = 49123;
+const TIMEOUT: Duration = Duration::from_secs(3);
+/// Seconds between 1900-01-01 and the unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+pub const DEFAULT_SERVER: &str = "pool.ntp.org";
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Asks `server` for the time, returning unix milliseconds corrected for half the round trip.
+pub async fn query(stack: Stack<'_>, server: &str) -> Option<u64> {
+    let address = match stack.dns_query(server, DnsQueryType::A).await {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        Ok(_) => return None,
+        Err(e) => {
+            warn!("could not resolve {} : {:?}", server, e);
+            return None;
+        }
+    };
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 64];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(LOCAL_PORT) {
+        warn!("sntp bind failed : {:?}", e);
+        return None;
+    }
+
+    let mut packet = [0; 48];
+    // leap indicator 0, version 4, client mode
+    packet[0] = 0x23;
+    let sent_at = Instant::now();
+    if let Err(e) = socket
+        .send_to(&packet, IpEndpoint::new(address, NTP_PORT))
+        .await
+    {
+        warn!("sntp request failed : {:?}", e);
+        return None;
+    }
+    let len = match with_timeout(TIMEOUT, socket.recv_from(&mut packet)).await {
+        Ok(Ok((len, _))) => len,
+        Ok(Err(e)) => {
+            warn!("sntp receive failed : {:?}", e);
+            return None;
+        }
+        Err(_) => {
+            warn!("sntp server {} did not answer", server);
+            return None;
+        }
+    };
+    let round_trip = Instant::now() - sent_at;
+    // server mode, and a stratum of 0 is a kiss of death
+    if len < 48 || packet[0] & 0x07 != 4 || packet[1] == 0 {
+        warn!("invalid sntp response");
+        return None;
+    }
+    let seconds = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]) as u64;
+    let fraction = u32::from_be_bytes([packet[44], packet[45], packet[46], packet[47]]) as u64;
+    let unix_ms = (seconds.checked_sub(NTP_UNIX_OFFSET)?) * 1000 + ((fraction * 1000) >> 32);
+    Some(unix_ms + round_trip.as_millis() / 2)
+}
+
+/// Keeps the clock synchronized from `server`.
+pub async fn run(stack: Stack<'_>, server: &str) -> ! {
+    loop {
+        stack.wait_config_up().await;
+        match query(stack, server).await {
+            Some(unix_ms) => {
+                clock::set_unix_ms(unix_ms, TimeSource::Sntp);
+                Timer::after(SYNC_INTERVAL).await;
+            }
+            None => Timer::after(RETRY_INTERVAL).await,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+//! Wi-Fi connection manager, driving the radio in [`run`].
+//!
+//! The network joined is the visible known network with the highest priority, the strongest
+//! one among equal priorities, then known networks not seen in the scan, which may be hidden.
+//! Joins are retried with an exponential backoff, and when the link drops the best known
+//! network is picked again, roaming to another one if the current one is gone.
+//! Connection changes are published on [`WIFI_STATE`], which the MQTT session follows through
+//! [`wait_connected`] and [`wait_disconnected`], and [`status_led`] shows on the onboard led,
+//! blinking while connected without an address, see [`ip::ADDRESS_MISSING`]. The led being a
+//! gpio of the radio, both tasks share it as a [`SharedControl`].
+//! Other tasks request scans through [`SCAN_REQUEST`], and the power management mode follows
+//! [`power::wanted`] while connected.
+//!
+//! cyw43 has no call to read the RSSI of the current association, so it is taken from a scan
+//! restricted to the joined network every [`RSSI_INTERVAL`].
+
+use core::sync::atomic::{AtomicI16, Ordering};
+
+use cyw43::{Control, JoinOptions, ScanOptions};
+use defmt::*;
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{String, Vec};
+
+use super::ip;
+use super::power::{self, PowerProfile};
+use crate::config::{KnownNetworks, WifiNetwork, MAX_NETWORKS};
+use crate::storage::SharedStorage;
+use crate::log_warn;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum WifiState {
+    Joining,
+    Connected,
+    /// The link dropped, or a join failed and is waiting to be retried.
+    Disconnected,
+}
+
+/// Latest connection state, for up to 4 receivers at a time : the status led, the MQTT
+/// session and a bluetooth connection.
+pub static WIFI_STATE: Watch<ThreadModeRawMutex, WifiState, 4> = Watch::new();
+
+/// The radio, shared by [`run`] and [`status_led`].
+pub type SharedControl = Mutex<ThreadModeRawMutex, Control<'static>>;
+
+/// Waits until the network is joined.
+pub async fn wait_connected() {
+    wait_until(true).await
+}
+
+/// Waits until the network is left, or was never joined.
+pub async fn wait_disconnected() {
+    wait_until(false).await
+}
+
+async fn wait_until(connected: bool) {
+    let mut states = unwrap!(WIFI_STATE.receiver(), "too many wifi state receivers");
+    let mut state = states.get().await;
+    while (state == WifiState::Connected) != connected {
+        state = states.changed().await;
+    }
+}
+
+const JOIN_RETRY_MIN: Duration = Duration::from_secs(1);
+const JOIN_RETRY_MAX: Duration = Duration::from_secs(60);
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// cyw43 gpio driving the onboard led of the Pico W.
+const STATUS_LED: u8 = 0;
+
+pub const MAX_SCAN_RESULTS: usize = 8;
+
+#[derive(Clone, PartialEq, Eq, Format)]
+pub struct Network {
+    pub ssid: String<32>,
+    /// dBm.
+    pub rssi: i16,
+    pub channel: u8,
+}
+
+pub type ScanResults = Vec<Network, MAX_SCAN_RESULTS>;
+
+pub const RSSI_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Last RSSI of the joined network, 0 until known.
+static RSSI: AtomicI16 = AtomicI16::new(0);
+
+/// Last known RSSI of the joined network, in dBm.
+pub fn rssi() -> Option<i16> {
+    match RSSI.load(Ordering::Relaxed) {
+        0 => None,
+        rssi => Some(rssi),
+    }
+}
+
+/// Set to have [`run`] scan, the results being signaled on [`SCAN_RESULTS`].
+pub static SCAN_REQUEST: Signal<ThreadModeRawMutex, ()> = Signal::new();
+pub static SCAN_RESULTS: Signal<ThreadModeRawMutex, ScanResults> = Signal::new();
+
+/// Visible networks, strongest first, keeping the strongest access point of each.
+pub async fn scan(control: &mut Control<'_>, options: ScanOptions) -> ScanResults {
+    let mut results = ScanResults::new();
+    let mut scanner = control.scan(options).await;
+    while let Some(bss) = scanner.next().await {
+        let ssid = match bss
+            .ssid
+            .get(..bss.ssid_len as usize)
+            .and_then(|ssid| core::str::from_utf8(ssid).ok())
+        {
+            // hidden networks
+            Some("") | None => continue,
+            Some(ssid) => ssid,
+        };
+        let mut network = Network {
+            ssid: String::new(),
+            rssi: bss.rssi,
+            // the low byte of the chanspec is the control channel
+            channel: (bss.chanspec & 0xff) as u8,
+        };
+        // at most 32 bytes
+        let _ = network.ssid.push_str(ssid);
+        debug!("AP: {}", network);
+        match results.iter_mut().find(|known| known.ssid == network.ssid) {
+            Some(known) if known.rssi < network.rssi => *known = network,
+            Some(_) => {}
+            None => {
+                if results.is_full() {
+                    let weakest = results.len() - 1;
+                    if results[weakest].rssi >= network.rssi {
+                        continue;
+                    }
+                    results.truncate(weakest);
+                }
+                let _ = results.push(network);
+            }
+        }
+        results.sort_unstable_by(|a, b| b.rssi.cmp(&a.rssi));
+    }
+    results
+}
+
+/// Options to scan for `ssid` alone.
+fn only(ssid: &str) -> ScanOptions {
+    let mut options = ScanOptions::default();
+    let mut name = String::new();
+    if name.push_str(ssid).is_ok() {
+        options.ssid = Some(name);
+    }
+    options
+}
+
+fn set_state(state: WifiState) {
+    info!("wifi : {}", state);
+    WIFI_STATE.sender().send(state);
+}
+
+/// Shows [`WIFI_STATE`] on the onboard led : lit while connected, blinking while connected
+/// without an address. The led lags behind while [`run`] holds the radio for a scan or a join.
+pub async fn status_led(control: &SharedControl) -> ! {
+    let mut states = unwrap!(WIFI_STATE.receiver(), "too many wifi state receivers");
+    let mut state = states.get().await;
+    let mut lit = false;
+    loop {
+        let connected = state == WifiState::Connected;
+        let wanted = if connected && ip::ADDRESS_MISSING.load(Ordering::Relaxed) {
+            !lit
+        } else {
+            connected
+        };
+        if wanted != lit {
+            control.lock().await.gpio_set(STATUS_LED, wanted).await;
+            lit = wanted;
+        }
+        // the address is checked again on the next tick
+        if let Either::First(changed) = select(states.changed(), Timer::after(LINK_CHECK_INTERVAL)).await {
+            state = changed;
+        }
+    }
+}
+
+/// Known networks in the order they should be tried given the scan `results`.
+pub fn rank<'a>(networks: &'a KnownNetworks, results: &ScanResults) -> Vec<&'a WifiNetwork, MAX_NETWORKS> {
+    let rssi = |network: &WifiNetwork| {
+        results
+            .iter()
+            .find(|visible| visible.ssid == network.ssid)
+            .map(|visible| visible.rssi)
+    };
+    let mut candidates: Vec<_, MAX_NETWORKS> = networks.iter().collect();
+    candidates.sort_unstable_by(|a, b| {
+        let (a_rssi, b_rssi) = (rssi(a), rssi(b));
+        // visible first, then by priority, then by signal
+        b_rssi
+            .is_some()
+            .cmp(&a_rssi.is_some())
+            .then(b.priority.cmp(&a.priority))
+            .then(b_rssi.cmp(&a_rssi))
+    });
+    candidates
+}
+
+async fn try_join(control: &mut Control<'_>, network: &WifiNetwork) -> bool {
+    let options = if network.password.is_empty() {
+        JoinOptions::new_open()
+    } else {
+        JoinOptions::new(network.password.expose().as_bytes())
+    };
+    match control.join(&network.ssid, options).await {
+        Ok(()) => true,
+        Err(e) => {
+            log_warn!("could not join {} : status {}", network.ssid, e.status);
+            false
+        }
+    }
+}
+
+/// Switches the power management mode if the profile wanted changed since `applied`.
+async fn apply_power(control: &SharedControl, applied: &mut Option<PowerProfile>) {
+    let wanted = power::wanted();
+    if *applied != Some(wanted) {
+        debug!("wifi power : {}", wanted);
+        control.lock().await.set_power_management(wanted.mode()).await;
+        *applied = Some(wanted);
+    }
+}
+
+/// Joins the best known network, retrying with a backoff until one succeeds.
+async fn join(control: &SharedControl, storage: &SharedStorage) -> String<32> {
+    let mut retry = JOIN_RETRY_MIN;
+    loop {
+        set_state(WifiState::Joining);
+        let networks = KnownNetworks::load(storage).await;
+        if networks.is_empty() {
+            log_warn!("no known wifi network");
+        }
+        let results = scan(&mut *control.lock().await, ScanOptions::default()).await;
+        for network in rank(&networks, &results) {
+            info!("joining {}", network.ssid);
+            if try_join(&mut *control.lock().await, network).await {
+                return network.ssid.clone();
+            }
+        }
+        set_state(WifiState::Disconnected);
+        Timer::after(retry).await;
+        retry = (retry * 2).min(JOIN_RETRY_MAX);
+    }
+}
+
+/// Keeps one of the networks known in `storage` joined, serving scan requests and keeping the
+/// RSSI up to date.
+pub async fn run(control: &SharedControl, stack: Stack<'static>, storage: &SharedStorage) -> ! {
+    let mut applied_power = None;
+    loop {
+        let joined = join(control, storage).await;
+        let ssid = joined.as_str();
+        // the link comes up once the join event went through the driver
+        stack.wait_link_up().await;
+        set_state(WifiState::Connected);
+        let mut next_rssi = Instant::now();
+        while stack.is_link_up() {
+            apply_power(control, &mut applied_power).await;
+            let wait = Timer::at(next_rssi.min(Instant::now() + LINK_CHECK_INTERVAL));
+            // the end of a burst is noticed on the next link check
+            let (results, requested) = match select3(SCAN_REQUEST.wait(), power::CHANGED.wait(), wait).await {
+                Either3::First(()) => (scan(&mut *control.lock().await, ScanOptions::default()).await, true),
+                Either3::Third(()) if Instant::now() >= next_rssi => {
+                    next_rssi = Instant::now() + RSSI_INTERVAL;
+                    (scan(&mut *control.lock().await, only(ssid)).await, false)
+                }
+                Either3::Second(()) | Either3::Third(()) => continue,
+            };
+            if let Some(network) = results.iter().find(|network| network.ssid == ssid) {
+                RSSI.store(network.rssi, Ordering::Relaxed);
+            }
+            if requested {
+                info!("{} networks found", results.len());
+                SCAN_RESULTS.signal(results);
+            }
+        }
+        log_warn!("wifi link to {} lost", ssid);
+        set_state(WifiState::Disconnected);
+        RSSI.store(0, Ordering::Relaxed);
+        control.lock().await.leave().await;
+    }
+}
@@ -4,7 +4,7 @@ use embassy_rp::{
 };
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub struct Color {
     pub red: u8,
     pub green: u8,
@@ -41,20 +41,62 @@ impl Color {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum Anim {
     None,
     Blink,
     Pulse,
 }
 
-#[derive(Clone, Copy)]
+impl Anim {
+    pub const ALL: [Anim; 3] = [Anim::None, Anim::Blink, Anim::Pulse];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Anim::None => "none",
+            Anim::Blink => "blink",
+            Anim::Pulse => "pulse",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|anim| anim.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct LedStatus {
     pub color: Option<Color>,
     pub power: Option<u8>,
     pub anim: Anim,
 }
 
+impl core::fmt::Display for Color {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl core::fmt::Display for Anim {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl LedStatus {
+    /// Color actually shown by the led once power is applied.
+    pub fn effective_color(&self) -> Color {
+        match (self.color, self.power) {
+            (Some(color), Some(power)) => color * power,
+            (Some(color), None) => color,
+            (None, Some(power)) => Color::new(power, power, power),
+            (None, None) => Color::off(),
+        }
+    }
+    pub fn is_on(&self) -> bool {
+        self.effective_color() != Color::off()
+    }
+}
+
 pub struct PwmRgbLed<'a> {
     pub red: PwmOutput<'a>,
     pub green: PwmOutput<'a>,
@@ -78,13 +120,7 @@ impl PwmRgbLed<'_> {
     pub async fn task(&mut self, signal: &'static Signal<ThreadModeRawMutex, LedStatus>) -> ! {
         loop {
             let message = signal.wait().await;
-            let color = match (message.color, message.power) {
-                (Some(color), Some(power)) => color * power,
-                (Some(color), None) => color,
-                (None, Some(power)) => Color::new(power, power, power),
-                (None, None) => Color::off(),
-            };
-            self.set_color(color);
+            self.set_color(message.effective_color());
         }
     }
 }
@@ -112,13 +148,7 @@ impl RgbLed<'_> {
     pub async fn task(&mut self, signal: &'static Signal<ThreadModeRawMutex, LedStatus>) -> ! {
         loop {
             let message = signal.wait().await;
-            let color = match (message.color, message.power) {
-                (Some(color), Some(power)) => color * power,
-                (Some(color), None) => color,
-                (None, Some(power)) => Color::new(power, power, power),
-                (None, None) => Color::off(),
-            };
-            self.set_color(color);
+            self.set_color(message.effective_color());
         }
     }
 }
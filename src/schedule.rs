@@ -0,0 +1,414 @@
+//! Local automations, received on `{prefix}/{chip_id}/schedule/<n>`, persisted in flash
+//! and run against the wall clock, also while the broker is unreachable.
+//!
+//! A rule is a JSON document such as
+//! `{"at":"22:00","days":"weekdays","led":1,"color":{"r":32,"g":0,"b":0},"brightness":26}`
+//! or `{"at":"sunset+15","led":1,"state":"ON"}`. `at` is a local `HH:MM` time, or `sunrise` /
+//! `sunset` with an optional offset in minutes. `days` is `daily` (the default), `weekdays`,
+//! `weekends` or a list such as `mon,wed,fri`. An empty payload deletes the rule.
+
+use core::convert::TryInto;
+
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Timer};
+use serde::Deserialize;
+
+use crate::clock;
+use crate::config::Location;
+use crate::input::messages::{ChannelMessage, InputChannel};
+use crate::mqtt::json::{JsonColor, LedCommand, MAX_JSON_PAYLOAD};
+use crate::output::leds::{Anim, Color};
+use crate::storage::{SharedStorage, Slot};
+
+pub const MAX_RULES: usize = 16;
+const RULE_SIZE: usize = 12;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Trigger {
+    /// Minutes after local midnight.
+    Time(u16),
+    /// Minutes after sunrise, or before if negative.
+    Sunrise(i16),
+    Sunset(i16),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct Action {
+    pub state: Option<bool>,
+    pub color: Option<Color>,
+    pub brightness: Option<u8>,
+    pub effect: Option<Anim>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub struct Rule {
+    pub trigger: Trigger,
+    /// Bit 0 is Monday.
+    pub days: u8,
+    pub led: u8,
+    pub action: Action,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum RuleError {
+    Json,
+    InvalidTime,
+    InvalidDays,
+    InvalidState,
+    InvalidEffect,
+    /// No led with that id.
+    InvalidLed,
+}
+
+#[derive(Deserialize)]
+struct RulePayload<'a> {
+    #[serde(borrow)]
+    at: &'a str,
+    #[serde(borrow, default)]
+    days: Option<&'a str>,
+    led: u8,
+    #[serde(borrow, default)]
+    state: Option<&'a str>,
+    #[serde(default)]
+    color: Option<JsonColor>,
+    #[serde(default)]
+    brightness: Option<u8>,
+    #[serde(borrow, default)]
+    effect: Option<&'a str>,
+}
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn parse_trigger(at: &str) -> Result<Trigger, RuleError> {
+    let sun = |rest: &str| -> Result<i16, RuleError> {
+        if rest.is_empty() {
+            Ok(0)
+        } else {
+            rest.trim_start_matches('+')
+                .parse()
+                .map_err(|_| RuleError::InvalidTime)
+        }
+    };
+    if let Some(rest) = at.strip_prefix("sunrise") {
+        return Ok(Trigger::Sunrise(sun(rest)?));
+    }
+    if let Some(rest) = at.strip_prefix("sunset") {
+        return Ok(Trigger::Sunset(sun(rest)?));
+    }
+    let (hours, minutes) = at.split_once(':').ok_or(RuleError::InvalidTime)?;
+    let hours: u16 = hours.parse().map_err(|_| RuleError::InvalidTime)?;
+    let minutes: u16 = minutes.parse().map_err(|_| RuleError::InvalidTime)?;
+    if hours > 23 || minutes > 59 {
+        return Err(RuleError::InvalidTime);
+    }
+    Ok(Trigger::Time(hours * 60 + minutes))
+}
+
+fn parse_days(days: &str) -> Result<u8, RuleError> {
+    match days {
+        "daily" => Ok(0x7f),
+        "weekdays" => Ok(0x1f),
+        "weekends" => Ok(0x60),
+        list => list.split(',').try_fold(0, |mask, day| {
+            let index = DAY_NAMES
+                .iter()
+                .position(|name| *name == day.trim())
+                .ok_or(RuleError::InvalidDays)?;
+            Ok(mask | 1 << index)
+        }),
+    }
+}
+
+impl Rule {
+    /// Parses a rule for one of the leds numbered from 1 to `led_count`.
+    pub fn parse(payload: &str, led_count: usize) -> Result<Self, RuleError> {
+        let (rule, _): (RulePayload, _) =
+            serde_json_core::from_str(payload).map_err(|_| RuleError::Json)?;
+        if rule.led == 0 || usize::from(rule.led) > led_count {
+            return Err(RuleError::InvalidLed);
+        }
+        let state = match rule.state {
+            None => None,
+            Some("ON") => Some(true),
+            Some("OFF") => Some(false),
+            Some(_) => return Err(RuleError::InvalidState),
+        };
+        let effect = match rule.effect {
+            None => None,
+            Some(name) => Some(Anim::from_name(name).ok_or(RuleError::InvalidEffect)?),
+        };
+        Ok(Rule {
+            trigger: parse_trigger(rule.at)?,
+            days: parse_days(rule.days.unwrap_or("daily"))?,
+            led: rule.led,
+            action: Action {
+                state,
+                color: rule.color.map(|JsonColor { r, g, b }| Color::new(r, g, b)),
+                brightness: rule.brightness,
+                effect,
+            },
+        })
+    }
+
+    fn encode(&self, out: &mut [u8; RULE_SIZE]) {
+        let (kind, value) = match self.trigger {
+            Trigger::Time(minutes) => (0, minutes as i16),
+            Trigger::Sunrise(offset) => (1, offset),
+            Trigger::Sunset(offset) => (2, offset),
+        };
+        let action = &self.action;
+        let color = action.color.unwrap_or(Color::off());
+        let flags = action.state.is_some() as u8
+            | (action.state == Some(true)) as u8 * 2
+            | action.color.is_some() as u8 * 4
+            | action.brightness.is_some() as u8 * 8
+            | action.effect.is_some() as u8 * 16;
+        let effect = Anim::ALL
+            .iter()
+            .position(|anim| Some(*anim) == action.effect)
+            .unwrap_or(0) as u8;
+        let value = value.to_le_bytes();
+        *out = [
+            1,
+            kind,
+            value[0],
+            value[1],
+            self.days,
+            self.led,
+            flags,
+            color.red,
+            color.green,
+            color.blue,
+            action.brightness.unwrap_or(0),
+            effect,
+        ];
+    }
+
+    fn decode(data: &[u8; RULE_SIZE]) -> Option<Self> {
+        if data[0] != 1 {
+            return None;
+        }
+        let value = i16::from_le_bytes([data[2], data[3]]);
+        let trigger = match data[1] {
+            0 => Trigger::Time(value as u16),
+            1 => Trigger::Sunrise(value),
+            2 => Trigger::Sunset(value),
+            _ => return None,
+        };
+        let flags = data[6];
+        Some(Rule {
+            trigger,
+            days: data[4],
+            led: data[5],
+            action: Action {
+                state: (flags & 1 != 0).then(|| flags & 2 != 0),
+                color: (flags & 4 != 0).then(|| Color::new(data[7], data[8], data[9])),
+                brightness: (flags & 8 != 0).then(|| data[10]),
+                effect: if flags & 16 != 0 {
+                    Some(*Anim::ALL.get(data[11] as usize)?)
+                } else {
+                    None
+                },
+            },
+        })
+    }
+
+    /// Minutes after local midnight the rule fires on the local day `day` (days since the epoch).
+    fn minute_of_day(&self, day: i64, local_offset_s: i32, location: Option<Location>) -> Option<i16> {
+        let (sunrise, offset) = match self.trigger {
+            Trigger::Time(minutes) => return Some(minutes as i16),
+            Trigger::Sunrise(offset) => (true, offset),
+            Trigger::Sunset(offset) => (false, offset),
+        };
+        let event = sun_event(day, location?, sunrise)?;
+        let local = (event + local_offset_s as i64).rem_euclid(86_400) / 60;
+        // offsets may move the rule across midnight
+        Some((local + offset as i64).rem_euclid(24 * 60) as i16)
+    }
+
+    fn command(&self) -> Option<ChannelMessage> {
+        let action = &self.action;
+        let command = LedCommand {
+            state: action.state.map(|on| if on { "ON" } else { "OFF" }),
+            color: action.color.map(|Color { red, green, blue }| JsonColor {
+                r: red,
+                g: green,
+                b: blue,
+            }),
+            brightness: action.brightness,
+            effect: action.effect.map(|anim| anim.name()),
+            transition: None,
+        };
+        let mut payload = [0; MAX_JSON_PAYLOAD];
+        let len = command.write(&mut payload).ok()?;
+        let mut id: heapless::String<8> = heapless::String::new();
+        core::fmt::write(&mut id, format_args!("{}", self.led)).ok()?;
+        ChannelMessage::new("led", &id, "set", core::str::from_utf8(&payload[..len]).ok()?).ok()
+    }
+}
+
+/// Unix time in seconds of the sunrise or sunset of the day containing `day`,
+/// from the sunrise equation, `None` during polar days and nights.
+fn sun_event(day: i64, location: Location, sunrise: bool) -> Option<i64> {
+    use libm::{acos, asin, cos, sin};
+    let rad = core::f64::consts::PI / 180.0;
+    let latitude = location.latitude as f64;
+    let longitude = location.longitude as f64;
+    // julian days since 2000-01-01 12:00, at noon on `day`
+    let n = (day - 10_957) as f64 + 0.0008;
+    let mean_noon = n - longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon) % 360.0;
+    let center = 1.9148 * sin(anomaly * rad)
+        + 0.02 * sin(2.0 * anomaly * rad)
+        + 0.0003 * sin(3.0 * anomaly * rad);
+    let ecliptic = (anomaly + center + 180.0 + 102.9372) % 360.0;
+    let transit =
+        mean_noon + 0.0053 * sin(anomaly * rad) - 0.0069 * sin(2.0 * ecliptic * rad);
+    let declination = asin(sin(ecliptic * rad) * sin(23.4397 * rad));
+    let cos_hour_angle = (sin(-0.833 * rad) - sin(latitude * rad) * sin(declination))
+        / (cos(latitude * rad) * cos(declination));
+    if !(-1.0..=1.0).contains(&cos_hour_angle) {
+        return None;
+    }
+    let hour_angle = acos(cos_hour_angle) / rad / 360.0;
+    let event = if sunrise {
+        transit - hour_angle
+    } else {
+        transit + hour_angle
+    };
+    // back from days since 2000-01-01 12:00 to unix seconds
+    Some(((event + 10_957.5) * 86_400.0) as i64)
+}
+
+pub struct Scheduler {
+    rules: [Option<Rule>; MAX_RULES],
+    led_count: usize,
+    location: Option<Location>,
+    /// Local minute, since the epoch, rules were last run for.
+    last_minute: Option<i64>,
+}
+
+impl Scheduler {
+    pub async fn load(storage: &SharedStorage, led_count: usize, location: Option<Location>) -> Self {
+        let mut scheduler = Scheduler {
+            rules: [None; MAX_RULES],
+            led_count,
+            location,
+            last_minute: None,
+        };
+        let mut buffer = [0; MAX_RULES * RULE_SIZE];
+        if let Some(data) = storage.lock().await.read(Slot::Schedules, &mut buffer) {
+            for (rule, chunk) in scheduler.rules.iter_mut().zip(data.chunks_exact(RULE_SIZE)) {
+                *rule = Rule::decode(chunk.try_into().unwrap());
+            }
+        }
+        info!(
+            "{} schedules loaded",
+            scheduler.rules.iter().filter(|rule| rule.is_some()).count()
+        );
+        scheduler
+    }
+
+    async fn save(&self, storage: &SharedStorage) {
+        let mut buffer = [0; MAX_RULES * RULE_SIZE];
+        for (rule, chunk) in self.rules.iter().zip(buffer.chunks_exact_mut(RULE_SIZE)) {
+            if let Some(rule) = rule {
+                rule.encode(chunk.try_into().unwrap());
+            }
+        }
+        if let Err(e) = storage.lock().await.write(Slot::Schedules, &buffer) {
+            error!("could not save schedules : {}", e);
+        }
+    }
+
+    /// Applies a rule update, returning true if the rules changed and need saving.
+    fn update(&mut self, message: &ChannelMessage) -> bool {
+        let index: usize = match message.id.parse() {
+            Ok(index) if index < MAX_RULES => index,
+            _ => {
+                warn!("invalid schedule id : {}", message.id);
+                return false;
+            }
+        };
+        if message.payload.is_empty() {
+            // retained schedules come again on every connection
+            if self.rules[index].is_none() {
+                return false;
+            }
+            info!("schedule {} deleted", index);
+            self.rules[index] = None;
+            return true;
+        }
+        match Rule::parse(&message.payload, self.led_count) {
+            Ok(rule) if matches!(rule.trigger, Trigger::Sunrise(_) | Trigger::Sunset(_))
+                && self.location.is_none() =>
+            {
+                warn!("schedule {} needs LATITUDE and LONGITUDE", index);
+                false
+            }
+            Ok(rule) if self.rules[index] == Some(rule) => false,
+            Ok(rule) => {
+                info!("schedule {} : {}", index, rule);
+                self.rules[index] = Some(rule);
+                true
+            }
+            Err(e) => {
+                warn!("invalid schedule {} : {}", index, e);
+                false
+            }
+        }
+    }
+
+    /// Sends the commands of the rules due this minute.
+    async fn tick(&mut self, output: &InputChannel) {
+        let now = match clock::now() {
+            Some(now) => now,
+            None => return,
+        };
+        let minute = now.local_seconds().div_euclid(60);
+        if self.last_minute == Some(minute) {
+            return;
+        }
+        self.last_minute = Some(minute);
+        let day = minute.div_euclid(24 * 60);
+        let minute_of_day = minute.rem_euclid(24 * 60) as i16;
+        let weekday = now.local().weekday;
+        for rule in self.rules.iter().flatten() {
+            if rule.days & 1 << weekday == 0 {
+                continue;
+            }
+            if rule.minute_of_day(day, now.local_offset_s, self.location) != Some(minute_of_day) {
+                continue;
+            }
+            debug!("running schedule {}", rule);
+            match rule.command() {
+                Some(command) => output.send(command).await,
+                None => warn!("schedule command does not fit a message"),
+            }
+        }
+    }
+
+    /// Applies rule updates from `updates` and runs the rules, sending led commands on `output`.
+    pub async fn run(
+        mut self,
+        storage: &SharedStorage,
+        updates: &InputChannel,
+        output: &InputChannel,
+    ) -> ! {
+        loop {
+            let wait = match clock::now() {
+                Some(now) => Duration::from_millis(60_000 - now.unix_ms % 60_000),
+                None => Duration::from_secs(60),
+            };
+            match select(updates.receive(), Timer::after(wait)).await {
+                Either::First(message) => {
+                    if self.update(&message) {
+                        self.save(storage).await;
+                    }
+                }
+                Either::Second(()) => self.tick(output).await,
+            }
+        }
+    }
+}
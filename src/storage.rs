@@ -0,0 +1,124 @@
+//! Settings persisted in the last 64KiB of the flash, one 4KiB sector per [`Slot`].
+//!
+//! The firmware and the cyw43 blobs flashed at 0x10100000 must stay below this region.
+
+use core::convert::TryFrom;
+
+use defmt::*;
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use heapless::Vec;
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+pub const STORAGE_OFFSET: u32 = (FLASH_SIZE - 64 * 1024) as u32;
+
+const MAGIC: [u8; 4] = *b"MPS1";
+const HEADER_SIZE: usize = 8;
+pub const MAX_RECORD_SIZE: usize = ERASE_SIZE - HEADER_SIZE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Slot {
+    Schedules = 0,
+    Groups = 1,
+    Networks = 2,
+    Mqtt = 3,
+    Bonds = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum StorageError {
+    TooLarge,
+    Flash,
+}
+
+pub struct Storage<'d> {
+    flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>,
+}
+
+pub type SharedStorage = Mutex<ThreadModeRawMutex, Storage<'static>>;
+
+/// Appends a length prefixed field to a record being built.
+pub fn push_field<const N: usize>(out: &mut Vec<u8, N>, field: &[u8]) -> Result<(), StorageError> {
+    let len = u8::try_from(field.len()).map_err(|_| StorageError::TooLarge)?;
+    out.push(len).map_err(|_| StorageError::TooLarge)?;
+    out.extend_from_slice(field)
+        .map_err(|_| StorageError::TooLarge)
+}
+
+/// Takes a length prefixed text field from the front of a record.
+pub fn take_field<'a>(data: &mut &'a [u8]) -> Option<&'a str> {
+    let (len, rest) = data.split_first()?;
+    let value = rest.get(..*len as usize)?;
+    *data = &rest[*len as usize..];
+    core::str::from_utf8(value).ok()
+}
+
+/// Fletcher-16, enough to tell an erased or torn sector from a record.
+fn checksum(data: &[u8]) -> u16 {
+    let (a, b) = data.iter().fold((0u16, 0u16), |(a, b), byte| {
+        let a = (a + *byte as u16) % 255;
+        (a, (b + a) % 255)
+    });
+    b << 8 | a
+}
+
+impl<'d> Storage<'d> {
+    pub fn new(flash: Flash<'d, FLASH, Blocking, FLASH_SIZE>) -> Self {
+        Self { flash }
+    }
+
+    fn offset(slot: Slot) -> u32 {
+        STORAGE_OFFSET + slot as u32 * ERASE_SIZE as u32
+    }
+
+    /// Reads the record of `slot` into `buffer`, `None` if it was never written or is corrupt.
+    pub fn read<'b>(&mut self, slot: Slot, buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        let offset = Self::offset(slot);
+        let mut header = [0; HEADER_SIZE];
+        self.flash.blocking_read(offset, &mut header).ok()?;
+        if header[..4] != MAGIC {
+            return None;
+        }
+        let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let expected = u16::from_le_bytes([header[6], header[7]]);
+        let data = buffer.get_mut(..len)?;
+        self.flash
+            .blocking_read(offset + HEADER_SIZE as u32, data)
+            .ok()?;
+        if checksum(data) != expected {
+            warn!("corrupt record in {}", slot);
+            return None;
+        }
+        Some(data)
+    }
+
+    pub fn write(&mut self, slot: Slot, data: &[u8]) -> Result<(), StorageError> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(StorageError::TooLarge);
+        }
+        let offset = Self::offset(slot);
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[6..8].copy_from_slice(&checksum(data).to_le_bytes());
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)?;
+        // data first, so a torn write leaves no valid header
+        self.flash
+            .blocking_write(offset + HEADER_SIZE as u32, data)
+            .map_err(|_| StorageError::Flash)?;
+        self.flash
+            .blocking_write(offset, &header)
+            .map_err(|_| StorageError::Flash)
+    }
+
+    pub fn erase(&mut self, slot: Slot) -> Result<(), StorageError> {
+        let offset = Self::offset(slot);
+        self.flash
+            .blocking_erase(offset, offset + ERASE_SIZE as u32)
+            .map_err(|_| StorageError::Flash)
+    }
+}
@@ -0,0 +1,150 @@
+//! Health report published periodically on `{prefix}/{chip_id}/telemetry`.
+//!
+//! The stack high-water mark is measured by painting the free stack at boot, see
+//! [`paint_stack`]. embassy-executor does not expose how much of its task arena is used, so the
+//! arena size is reported with the RAM taken by statics, which includes it.
+
+use core::ptr::addr_of_mut;
+
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::Serialize;
+
+use crate::net::wifi;
+
+pub const TELEMETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+static PARSE_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Counts a broker session started after the first one.
+pub fn record_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts an incoming message that could not be understood.
+pub fn record_parse_error() {
+    PARSE_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+const STACK_PAINT: u32 = 0xcccc_cccc;
+
+extern "C" {
+    // from the cortex-m-rt linker script, the stack grows down from `_stack_start` towards `__sheap`
+    static mut __sheap: u32;
+    static mut _stack_start: u32;
+    static mut __sdata: u32;
+    static mut __edata: u32;
+    static mut __sbss: u32;
+    static mut __ebss: u32;
+}
+
+/// Fills the free stack with a pattern, so [`stack_usage`] can find how deep it went.
+/// Call first thing in `main`.
+pub fn paint_stack() {
+    // SAFETY: only the memory below the current stack pointer, minus a margin for this
+    // function, is written, and nothing lives there yet
+    unsafe {
+        let mut word = addr_of_mut!(__sheap);
+        let end = (cortex_m::register::msp::read() as *mut u32).sub(64);
+        while word < end {
+            word.write_volatile(STACK_PAINT);
+            word = word.add(1);
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, defmt::Format)]
+pub struct StackUsage {
+    /// Deepest the stack went, in bytes.
+    pub used: usize,
+    pub size: usize,
+}
+
+pub fn stack_usage() -> StackUsage {
+    // SAFETY: reads stay between the linker provided bounds of the stack
+    unsafe {
+        let bottom = addr_of_mut!(__sheap);
+        let top = addr_of_mut!(_stack_start);
+        let mut word = bottom;
+        while word < top && word.read_volatile() == STACK_PAINT {
+            word = word.add(1);
+        }
+        StackUsage {
+            used: top as usize - word as usize,
+            size: top as usize - bottom as usize,
+        }
+    }
+}
+
+/// Size of the executor task arena, from the `task-arena-size-*` feature of embassy-executor.
+pub const TASK_ARENA_SIZE: usize = parse_size(env!("TASK_ARENA_SIZE"));
+
+const fn parse_size(digits: &str) -> usize {
+    let digits = digits.as_bytes();
+    let mut size = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        size = size * 10 + (digits[i] - b'0') as usize;
+        i += 1;
+    }
+    size
+}
+
+#[derive(Serialize, Clone, Copy, defmt::Format)]
+pub struct TaskMemory {
+    /// Arena the task pools are allocated from.
+    pub arena_size: usize,
+    /// RAM taken by all statics, the task arena included.
+    pub statics: usize,
+}
+
+pub fn task_memory() -> TaskMemory {
+    // SAFETY: only the addresses of the linker provided symbols are taken
+    let statics = unsafe {
+        (addr_of_mut!(__edata) as usize - addr_of_mut!(__sdata) as usize)
+            + (addr_of_mut!(__ebss) as usize - addr_of_mut!(__sbss) as usize)
+    };
+    TaskMemory {
+        arena_size: TASK_ARENA_SIZE,
+        statics,
+    }
+}
+
+#[derive(Serialize)]
+pub struct Telemetry<'a> {
+    pub version: &'a str,
+    pub uptime_s: u64,
+    pub rssi: Option<i16>,
+    pub address: &'a str,
+    pub reconnects: u32,
+    pub parse_errors: u32,
+    pub stack: StackUsage,
+    pub tasks: TaskMemory,
+}
+
+impl<'a> Telemetry<'a> {
+    /// Takes the current readings, `address` being a buffer for the IP address.
+    pub fn collect(stack: Stack<'_>, address: &'a mut String<48>) -> Self {
+        address.clear();
+        if let Some(config) = stack.config_v4() {
+            let _ = core::fmt::write(address, format_args!("{}", config.address));
+        }
+        Telemetry {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_s: Instant::now().as_secs(),
+            rssi: wifi::rssi(),
+            address,
+            reconnects: RECONNECTS.load(Ordering::Relaxed),
+            parse_errors: PARSE_ERRORS.load(Ordering::Relaxed),
+            stack: stack_usage(),
+            tasks: task_memory(),
+        }
+    }
+
+    pub fn write(&self, buffer: &mut [u8]) -> Result<usize, serde_json_core::ser::Error> {
+        serde_json_core::to_slice(self, buffer)
+    }
+}
@@ -0,0 +1,67 @@
+#!/usr/bin/env python3
+"""Packages the cyw43 firmwares with the header checked at boot, see src/firmware.rs.
+
+    tools/pack_firmware.py ../embassy/cyw43-firmware --version 1
+
+writes 43439A0.pack, 43439A0_clm.pack and 43439A0_btfw.pack, then prints the probe-rs
+commands flashing them where src/board.rs reads them.
+"""
+
+import argparse
+import struct
+import sys
+import zlib
+from pathlib import Path
+
+MAGIC = b"CYWF"
+FORMAT = 1
+HEADER = struct.Struct("<4sHHIII12x")
+
+# name, kind, flash address, region size, as in src/board.rs
+BLOBS = [
+    ("43439A0", 0, 0x10100000, 0x40000),
+    ("43439A0_clm", 1, 0x10140000, 0x1400),
+    ("43439A0_btfw", 2, 0x10141400, 0x2000),
+]
+
+
+def pack(blob: bytes, kind: int, version: int) -> bytes:
+    crc = zlib.crc32(blob) & 0xFFFFFFFF
+    return HEADER.pack(MAGIC, FORMAT, kind, version, len(blob), crc) + blob
+
+
+def main() -> int:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("firmware_dir", type=Path, help="directory holding the .bin blobs")
+    parser.add_argument("--version", type=int, default=0, help="version stored in the headers")
+    parser.add_argument("--out", type=Path, default=Path("."), help="where to write the packages")
+    args = parser.parse_args()
+
+    if not 0 <= args.version <= 0xFFFFFFFF:
+        parser.error("the version must fit 32 bits")
+    args.out.mkdir(parents=True, exist_ok=True)
+    commands = []
+    for name, kind, address, size in BLOBS:
+        source = args.firmware_dir / f"{name}.bin"
+        try:
+            blob = source.read_bytes()
+        except OSError as e:
+            print(f"could not read {source} : {e}", file=sys.stderr)
+            return 1
+        package = pack(blob, kind, args.version)
+        if len(package) > size:
+            print(f"{source} is {len(blob)} bytes, over its {size} bytes region", file=sys.stderr)
+            return 1
+        target = args.out / f"{name}.pack"
+        target.write_bytes(package)
+        print(f"{target} : {len(blob)} bytes, crc32 {zlib.crc32(blob) & 0xFFFFFFFF:08x}")
+        commands.append(
+            f"probe-rs download {target} --binary-format bin --chip RP2040 --base-address {address:#x}"
+        )
+    print()
+    print("\n".join(commands))
+    return 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())